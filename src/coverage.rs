@@ -0,0 +1,433 @@
+// Licensed under the Apache License, Version 2.0
+
+use anyhow::{bail, Context, Result};
+
+use crate::init::xml_escape;
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
+
+/// The directory that the raw profiles of the instrumented tests are written to.
+fn profraw_dir(coverage_dir: &Path) -> PathBuf {
+    coverage_dir.join("profraw")
+}
+
+/// Prepares a coverage directory and returns the environment variables that make cargo build
+/// instrumented tests, which write their raw profiles to that directory.
+///
+/// Raw profiles of earlier test runs are removed, so that they do not end up in the report.
+pub fn prepare_coverage(coverage_dir: impl AsRef<Path>) -> Result<Vec<(&'static str, String)>> {
+    let profraw_dir = profraw_dir(coverage_dir.as_ref());
+    if profraw_dir.exists() {
+        std::fs::remove_dir_all(&profraw_dir)
+            .with_context(|| format!("Failed to remove '{}'", profraw_dir.display()))?;
+    }
+    std::fs::create_dir_all(&profraw_dir)
+        .with_context(|| format!("Failed to create '{}'", profraw_dir.display()))?;
+    Ok(vec![
+        instrumented_rustflags(|name| std::env::var(name).ok()),
+        (
+            "LLVM_PROFILE_FILE",
+            profraw_dir
+                .join("%p-%m.profraw")
+                .to_string_lossy()
+                .into_owned(),
+        ),
+    ])
+}
+
+/// The environment variable that adds `-C instrument-coverage` to the flags that cargo passes to
+/// rustc, given the environment variables of this process.
+///
+/// Cargo takes the flags from the first of `CARGO_ENCODED_RUSTFLAGS`, `RUSTFLAGS`,
+/// `target.<triple>.rustflags` and `build.rustflags` that is set, so the flag is added to the
+/// environment variable that is set. Otherwise it is added to `build.rustflags` with
+/// `CARGO_BUILD_RUSTFLAGS`, which cargo joins with `build.rustflags` from its config files. The
+/// flag is therefore missing if only `target.<triple>.rustflags` are configured.
+fn instrumented_rustflags(var: impl Fn(&str) -> Option<String>) -> (&'static str, String) {
+    if let Some(mut encoded) = var("CARGO_ENCODED_RUSTFLAGS") {
+        // The flags are separated by the ASCII unit separator
+        if !encoded.is_empty() {
+            encoded.push('\x1f');
+        }
+        encoded.push_str("-C\x1finstrument-coverage");
+        return ("CARGO_ENCODED_RUSTFLAGS", encoded);
+    }
+    let (name, flags) = match var("RUSTFLAGS") {
+        Some(flags) => ("RUSTFLAGS", flags),
+        None => (
+            "CARGO_BUILD_RUSTFLAGS",
+            var("CARGO_BUILD_RUSTFLAGS").unwrap_or_default(),
+        ),
+    };
+    let mut flags = flags.trim().to_owned();
+    if !flags.is_empty() {
+        flags.push(' ');
+    }
+    flags.push_str("-C instrument-coverage");
+    (name, flags)
+}
+
+/// Changes the arguments for cargo so that the instrumented tests are built in a target
+/// directory inside the coverage directory.
+///
+/// Cargo rebuilds everything when `RUSTFLAGS` change, so sharing the target directory with
+/// builds without coverage would rebuild the dependencies every time the flags switch. A
+/// `--target-dir` among the arguments is replaced, and arguments after `--` are kept as they are.
+pub fn coverage_args(args: &[OsString], coverage_dir: impl AsRef<Path>) -> Vec<OsString> {
+    let mut coverage_args = vec![
+        OsString::from("--target-dir"),
+        coverage_dir.as_ref().join("target").into(),
+    ];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            coverage_args.push(arg.clone());
+            coverage_args.extend(args.cloned());
+            break;
+        }
+        if arg == "--target-dir" {
+            args.next();
+        } else if !arg.to_string_lossy().starts_with("--target-dir=") {
+            coverage_args.push(arg.clone());
+        }
+    }
+    coverage_args
+}
+
+/// Merges the raw profiles of a test run and writes the coverage of the given packages to
+/// `lcov.info` and `cobertura.xml` in the coverage directory.
+///
+/// `objects` are the instrumented test executables, and `packages` the names and directories of
+/// the packages whose source files are reported.
+pub fn write_coverage_reports(
+    coverage_dir: impl AsRef<Path>,
+    objects: &[PathBuf],
+    packages: &[(&str, &Path)],
+) -> Result<()> {
+    let coverage_dir = coverage_dir.as_ref();
+    if objects.is_empty() {
+        bail!("No test executables were built, so there is no coverage to report");
+    }
+    let profiles: Vec<_> = std::fs::read_dir(profraw_dir(coverage_dir))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<_>>()?;
+    if profiles.is_empty() {
+        bail!("The tests did not write any coverage profiles");
+    }
+    let profdata = coverage_dir.join("cargo_test.profdata");
+    run_llvm_tool(
+        Command::new(llvm_tool("llvm-profdata")?)
+            .args(["merge", "-sparse", "-o"])
+            .arg(&profdata)
+            .args(&profiles),
+    )?;
+    let mut cmd = Command::new(llvm_tool("llvm-cov")?);
+    cmd.args(["export", "-format=lcov", "-instr-profile"])
+        .arg(&profdata)
+        .arg(&objects[0]);
+    for object in &objects[1..] {
+        cmd.arg("-object").arg(object);
+    }
+    let lcov = run_llvm_tool(&mut cmd)?;
+
+    let mut files: Vec<_> = parse_lcov(&lcov)
+        .into_iter()
+        .filter(|file| packages.iter().any(|(_, dir)| file.path.starts_with(dir)))
+        .collect();
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    let lcov: String = files.iter().map(|file| file.record.as_str()).collect();
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    let cobertura = cobertura_xml(&files, packages, timestamp);
+    for (name, contents) in [("lcov.info", lcov), ("cobertura.xml", cobertura)] {
+        let path = coverage_dir.join(name);
+        std::fs::write(&path, contents)
+            .with_context(|| format!("Failed to write '{}'", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Finds an LLVM tool of the Rust toolchain, which the `llvm-tools` rustup component provides.
+///
+/// Falls back to the tool on the PATH, which only works if it uses the same LLVM version as rustc.
+fn llvm_tool(name: &str) -> Result<PathBuf> {
+    let output = Command::new("rustc")
+        .args(["--print", "target-libdir"])
+        .output()
+        .context("Failed to run 'rustc --print target-libdir'")?;
+    // The tools are next to the host's libraries, in `lib/rustlib/<host>/bin`
+    let libdir = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+    if let Some(bin_dir) = libdir.parent().map(|dir| dir.join("bin")) {
+        let tool = bin_dir
+            .join(name)
+            .with_extension(std::env::consts::EXE_EXTENSION);
+        if tool.is_file() {
+            return Ok(tool);
+        }
+    }
+    let found_on_path = Command::new(name).arg("--version").output().is_ok();
+    if !found_on_path {
+        bail!("'{name}' was not found, install it with 'rustup component add llvm-tools'");
+    }
+    Ok(PathBuf::from(name))
+}
+
+/// Runs an LLVM tool and returns its output.
+fn run_llvm_tool(cmd: &mut Command) -> Result<String> {
+    let program = cmd.get_program().to_string_lossy().into_owned();
+    let output = cmd
+        .output()
+        .with_context(|| format!("Failed to run '{program}'"))?;
+    if !output.status.success() {
+        bail!(
+            "'{program}' failed:\n{}",
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+    }
+    String::from_utf8(output.stdout).with_context(|| format!("Invalid output of '{program}'"))
+}
+
+/// The coverage of one source file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileCoverage {
+    pub path: PathBuf,
+    /// How often each line was run, by line number
+    pub lines: BTreeMap<u32, u64>,
+    /// The lcov record of the file, from `SF:` to `end_of_record`
+    pub record: String,
+}
+
+/// Parses lcov tracefile data into the coverage of each source file.
+pub fn parse_lcov(lcov: &str) -> Vec<FileCoverage> {
+    let mut files = Vec::new();
+    let mut current: Option<FileCoverage> = None;
+    for line in lcov.lines() {
+        if let Some(path) = line.strip_prefix("SF:") {
+            current = Some(FileCoverage {
+                path: PathBuf::from(path),
+                lines: BTreeMap::new(),
+                record: String::new(),
+            });
+        }
+        let Some(file) = &mut current else {
+            continue;
+        };
+        file.record.push_str(line);
+        file.record.push('\n');
+        if let Some((number, hits)) = line
+            .strip_prefix("DA:")
+            .and_then(|data| data.split(',').next().zip(data.split(',').nth(1)))
+        {
+            if let (Ok(number), Ok(hits)) = (number.parse(), hits.parse::<u64>()) {
+                *file.lines.entry(number).or_default() += hits;
+            }
+        } else if line == "end_of_record" {
+            files.extend(current.take());
+        }
+    }
+    files
+}
+
+/// Converts the coverage of the source files to a Cobertura report, with one Cobertura package
+/// per ROS package.
+pub fn cobertura_xml(files: &[FileCoverage], packages: &[(&str, &Path)], timestamp: u64) -> String {
+    let rate = |files: &[&FileCoverage]| {
+        let valid: usize = files.iter().map(|file| file.lines.len()).sum();
+        let covered: usize = files
+            .iter()
+            .map(|file| file.lines.values().filter(|&&hits| hits > 0).count())
+            .sum();
+        let rate = if valid == 0 {
+            1.0
+        } else {
+            covered as f64 / valid as f64
+        };
+        (rate, covered, valid)
+    };
+    let all_files: Vec<_> = files.iter().collect();
+    let (line_rate, covered, valid) = rate(&all_files);
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(
+        "<!DOCTYPE coverage SYSTEM \"http://cobertura.sourceforge.net/xml/coverage-04.dtd\">\n",
+    );
+    // unwrap is ok since writing to a string cannot fail
+    writeln!(
+        xml,
+        "<coverage line-rate=\"{line_rate:.4}\" branch-rate=\"0\" lines-covered=\"{covered}\" \
+         lines-valid=\"{valid}\" branches-covered=\"0\" branches-valid=\"0\" complexity=\"0\" \
+         version=\"0\" timestamp=\"{timestamp}\">"
+    )
+    .unwrap();
+    xml.push_str("  <sources>\n");
+    for (_, dir) in packages {
+        writeln!(
+            xml,
+            "    <source>{}</source>",
+            xml_escape(&dir.to_string_lossy())
+        )
+        .unwrap();
+    }
+    xml.push_str("  </sources>\n  <packages>\n");
+    for (name, dir) in packages {
+        let package_files: Vec<_> = files
+            .iter()
+            .filter(|file| file.path.starts_with(dir))
+            .collect();
+        let (line_rate, _, _) = rate(&package_files);
+        writeln!(
+            xml,
+            "    <package name=\"{}\" line-rate=\"{line_rate:.4}\" branch-rate=\"0\" complexity=\"0\">\n      <classes>",
+            xml_escape(name)
+        )
+        .unwrap();
+        for file in package_files {
+            // unwrap is ok since only files in the package directory are included
+            let filename = file.path.strip_prefix(dir).unwrap().to_string_lossy();
+            let (line_rate, _, _) = rate(&[file]);
+            writeln!(
+                xml,
+                "        <class name=\"{}\" filename=\"{}\" line-rate=\"{line_rate:.4}\" branch-rate=\"0\" complexity=\"0\">\n          <methods/>\n          <lines>",
+                xml_escape(&filename.replace(['/', '\\'], ".")),
+                xml_escape(&filename)
+            )
+            .unwrap();
+            for (number, hits) in &file.lines {
+                writeln!(
+                    xml,
+                    "            <line number=\"{number}\" hits=\"{hits}\" branch=\"false\"/>"
+                )
+                .unwrap();
+            }
+            xml.push_str("          </lines>\n        </class>\n");
+        }
+        xml.push_str("      </classes>\n    </package>\n");
+    }
+    xml.push_str("  </packages>\n</coverage>\n");
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LCOV: &str = "SF:/ws/my_package/src/lib.rs
+FN:1,add
+FNDA:2,add
+DA:1,2
+DA:2,2
+DA:5,0
+LF:3
+LH:2
+end_of_record
+SF:/home/user/.cargo/registry/src/dep/lib.rs
+DA:1,1
+end_of_record
+";
+
+    #[test]
+    fn test_instrumented_rustflags() {
+        let env = |vars: &[(&str, &str)]| {
+            let vars: Vec<_> = vars
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect();
+            instrumented_rustflags(move |name| {
+                vars.iter()
+                    .find(|(var, _)| var == name)
+                    .map(|(_, value)| value.clone())
+            })
+        };
+        assert_eq!(
+            env(&[]),
+            ("CARGO_BUILD_RUSTFLAGS", "-C instrument-coverage".to_owned())
+        );
+        assert_eq!(
+            env(&[("RUSTFLAGS", "--cfg ci")]),
+            ("RUSTFLAGS", "--cfg ci -C instrument-coverage".to_owned())
+        );
+        assert_eq!(
+            env(&[
+                ("CARGO_ENCODED_RUSTFLAGS", "--cfg\x1fci"),
+                ("RUSTFLAGS", "--cfg other")
+            ]),
+            (
+                "CARGO_ENCODED_RUSTFLAGS",
+                "--cfg\x1fci\x1f-C\x1finstrument-coverage".to_owned()
+            )
+        );
+        // An empty variable still takes precedence
+        assert_eq!(
+            env(&[("CARGO_ENCODED_RUSTFLAGS", ""), ("RUSTFLAGS", "--cfg ci")]),
+            (
+                "CARGO_ENCODED_RUSTFLAGS",
+                "-C\x1finstrument-coverage".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn test_coverage_args() {
+        let args: Vec<OsString> = ["--target-dir", "build", "--target-dir=other", "-p", "pkg"]
+            .into_iter()
+            .chain(["--", "--target-dir", "x"])
+            .map(OsString::from)
+            .collect();
+        assert_eq!(
+            coverage_args(&args, "build/coverage"),
+            [
+                "--target-dir",
+                "build/coverage/target",
+                "-p",
+                "pkg",
+                "--",
+                "--target-dir",
+                "x"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_lcov() {
+        let files = parse_lcov(LCOV);
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].path, Path::new("/ws/my_package/src/lib.rs"));
+        assert_eq!(files[0].lines, BTreeMap::from([(1, 2), (2, 2), (5, 0)]));
+        assert!(files[0].record.starts_with("SF:"));
+        assert!(files[0].record.ends_with("end_of_record\n"));
+        assert_eq!(files[0].record.lines().count(), 9);
+    }
+
+    #[test]
+    fn test_cobertura_xml() {
+        let files = parse_lcov(LCOV);
+        let packages = [("my_package", Path::new("/ws/my_package"))];
+        let xml = cobertura_xml(&files[..1], &packages, 1700000000);
+        let options = roxmltree::ParsingOptions {
+            allow_dtd: true,
+            ..Default::default()
+        };
+        let document = roxmltree::Document::parse_with_options(&xml, options).unwrap();
+        let coverage = document.root_element();
+        assert_eq!(coverage.attribute("lines-covered"), Some("2"));
+        assert_eq!(coverage.attribute("lines-valid"), Some("3"));
+        assert_eq!(coverage.attribute("line-rate"), Some("0.6667"));
+        let class = coverage
+            .descendants()
+            .find(|node| node.has_tag_name("class"))
+            .unwrap();
+        assert_eq!(class.attribute("filename"), Some("src/lib.rs"));
+        assert_eq!(class.attribute("name"), Some("src.lib.rs"));
+        let lines = class
+            .descendants()
+            .filter(|node| node.has_tag_name("line"))
+            .count();
+        assert_eq!(lines, 3);
+    }
+}
@@ -0,0 +1,148 @@
+// Licensed under the Apache License, Version 2.0
+
+use anyhow::{bail, Context, Result};
+use fs4::{FileExt, TryLockError};
+
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+/// The domain IDs that are handed out, which are valid on all platforms. Domain 0 is left out,
+/// since it is the default that other ROS applications on the machine use.
+const DOMAIN_IDS: RangeInclusive<u32> = 1..=101;
+
+/// A `ROS_DOMAIN_ID` that is reserved for one test run, like `run_test_isolated` of
+/// ament_cmake_ros does
+///
+/// The reservation is an exclusive advisory lock on the file `<domain ID>.lock`, which the
+/// operating system releases when this is dropped or the process ends, e.g. when a test run
+/// crashes. The lock files themselves are kept, since another process may be about to lock a
+/// file that is removed.
+#[derive(Debug)]
+pub struct DomainIdLock {
+    domain_id: u32,
+    /// The locked file, which is unlocked when it is closed
+    _file: File,
+}
+
+impl DomainIdLock {
+    /// The directory that the lock files are created in by default.
+    ///
+    /// It is shared by all workspaces and users instead of being inside the build base, since
+    /// colcon gives each package a build base of its own, and tests of different build bases,
+    /// e.g. of packages that colcon tests in parallel, interfere on the same domain just as well.
+    pub fn default_lock_dir() -> PathBuf {
+        std::env::temp_dir().join("cargo-ament-build-domain-ids")
+    }
+
+    /// Reserves the lowest domain ID that no other test run is using.
+    pub fn acquire(lock_dir: impl AsRef<Path>) -> Result<Self> {
+        let lock_dir = lock_dir.as_ref();
+        create_lock_dir(lock_dir).with_context(|| {
+            format!(
+                "Failed to create domain ID lock directory '{}'",
+                lock_dir.display()
+            )
+        })?;
+        for domain_id in DOMAIN_IDS {
+            let path = lock_dir.join(format!("{domain_id}.lock"));
+            let file = open_lock_file(&path)
+                .with_context(|| format!("Failed to open '{}'", path.display()))?;
+            // Newer toolchains have a `File::try_lock` of their own, which would take precedence
+            match FileExt::try_lock(&file) {
+                Ok(()) => {
+                    return Ok(Self {
+                        domain_id,
+                        _file: file,
+                    })
+                }
+                Err(TryLockError::WouldBlock) => continue,
+                Err(TryLockError::Error(e)) => {
+                    return Err(e).with_context(|| format!("Failed to lock '{}'", path.display()))
+                }
+            }
+        }
+        bail!(
+            "All ROS domain IDs from {} to {} are in use by other test runs",
+            DOMAIN_IDS.start(),
+            DOMAIN_IDS.end()
+        )
+    }
+
+    /// The reserved domain ID.
+    pub fn domain_id(&self) -> u32 {
+        self.domain_id
+    }
+}
+
+/// Creates the lock directory, which everyone can create files in, like the temporary directory.
+fn create_lock_dir(lock_dir: &Path) -> std::io::Result<()> {
+    if let Some(parent) = lock_dir.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    match std::fs::create_dir(lock_dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => return Ok(()),
+        Err(e) => return Err(e),
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        // With the sticky bit, users can only remove their own lock files
+        std::fs::set_permissions(lock_dir, std::fs::Permissions::from_mode(0o1777))?;
+    }
+    Ok(())
+}
+
+/// Opens a lock file, creating it if it does not exist yet.
+///
+/// Lock files of other users can only be read, which is enough to lock them.
+fn open_lock_file(path: &Path) -> std::io::Result<File> {
+    match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+    {
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => File::open(path),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_acquire_unique_domain_ids() -> Result<()> {
+        let tmp = tempdir()?;
+        let first = DomainIdLock::acquire(tmp.path())?;
+        let second = DomainIdLock::acquire(tmp.path())?;
+        assert_eq!(first.domain_id(), 1);
+        assert_eq!(second.domain_id(), 2);
+
+        // Released domain IDs are reused, although their lock files are kept
+        drop(first);
+        assert!(tmp.path().join("1.lock").exists());
+        assert_eq!(DomainIdLock::acquire(tmp.path())?.domain_id(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_acquire_skips_domain_ids_locked_elsewhere() -> Result<()> {
+        let tmp = tempdir()?;
+        // A lock held through another file handle, like another process would hold it
+        let other = open_lock_file(&tmp.path().join("1.lock"))?;
+        FileExt::try_lock(&other)?;
+
+        let second = DomainIdLock::acquire(tmp.path())?;
+        assert_eq!(second.domain_id(), 2);
+        // A lock file that is not locked, e.g. of a crashed test run, is no reservation
+        File::create(tmp.path().join("3.lock"))?;
+        assert_eq!(DomainIdLock::acquire(tmp.path())?.domain_id(), 3);
+        Ok(())
+    }
+}
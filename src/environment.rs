@@ -0,0 +1,464 @@
+// Licensed under the Apache License, Version 2.0
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+use crate::{install_contents, InstallManifest};
+
+use std::path::{Component, Path};
+
+/// A change to an environment variable made when the install space is sourced.
+///
+/// Paths are relative to the install prefix, where an empty path stands for the prefix itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnvironmentOperation {
+    /// Prepends a path, unless it is already contained
+    PrependNonDuplicate { variable: String, path: String },
+    /// Appends a path, unless it is already contained
+    AppendNonDuplicate { variable: String, path: String },
+    /// Sets the variable to a path
+    SetPath { variable: String, path: String },
+    /// Sets the variable to a literal value
+    Set { variable: String, value: String },
+}
+
+impl EnvironmentOperation {
+    /// The line of this operation in a colcon `.dsv` file.
+    fn dsv_line(&self) -> String {
+        match self {
+            Self::PrependNonDuplicate { variable, path } => {
+                format!("prepend-non-duplicate;{variable};{path}")
+            }
+            Self::AppendNonDuplicate { variable, path } => {
+                format!("append-non-duplicate;{variable};{path}")
+            }
+            // colcon turns the value into an absolute path if it exists in the prefix
+            Self::SetPath {
+                variable,
+                path: value,
+            }
+            | Self::Set { variable, value } => format!("set;{variable};{value}"),
+        }
+    }
+
+    /// The line of this operation in a POSIX shell hook, as sourced by `local_setup.sh`.
+    fn sh_line(&self) -> String {
+        match self {
+            Self::PrependNonDuplicate { variable, path } => {
+                format!("ament_prepend_unique_value {variable} {}", sh_path(path))
+            }
+            Self::AppendNonDuplicate { variable, path } => {
+                format!("ament_append_unique_value {variable} {}", sh_path(path))
+            }
+            Self::SetPath { variable, path } => format!("export {variable}={}", sh_path(path)),
+            Self::Set { variable, value } => format!("export {variable}={}", sh_quote(value)),
+        }
+    }
+}
+
+/// An ament environment hook, installed to `share/<package>/environment/<name>.{dsv,sh}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvironmentHook {
+    pub name: String,
+    pub operations: Vec<EnvironmentOperation>,
+}
+
+/// The environment variable that shared libraries are looked up in.
+const LIBRARY_PATH_VARIABLE: &str = if cfg!(target_os = "macos") {
+    "DYLD_LIBRARY_PATH"
+} else if cfg!(windows) {
+    "PATH"
+} else {
+    "LD_LIBRARY_PATH"
+};
+
+/// Determine the environment hooks that every package gets, like ament_cmake packages do
+///
+/// Every package adds its prefix to `AMENT_PREFIX_PATH`. If shared libraries are installed,
+/// their directory `lib/<package>` is added to the library path.
+pub fn environment_hooks(package_name: &str, has_shared_libraries: bool) -> Vec<EnvironmentHook> {
+    let mut hooks = vec![EnvironmentHook {
+        name: String::from("ament_prefix_path"),
+        operations: vec![EnvironmentOperation::PrependNonDuplicate {
+            variable: String::from("AMENT_PREFIX_PATH"),
+            path: String::new(),
+        }],
+    }];
+    if has_shared_libraries {
+        hooks.push(EnvironmentHook {
+            name: String::from("library_path"),
+            operations: vec![EnvironmentOperation::PrependNonDuplicate {
+                variable: String::from(LIBRARY_PATH_VARIABLE),
+                path: format!("lib/{package_name}"),
+            }],
+        });
+    }
+    hooks
+}
+
+/// Read the `[package.metadata.ros.environment]` table into a hook
+///
+/// Each entry is either a literal value to set the variable to, or a table with one of the
+/// keys `prepend`, `append` and `set`, whose value is a path relative to the install prefix, or
+/// for `prepend` and `append` also a list of them:
+/// ```toml
+/// [package.metadata.ros.environment]
+/// RUST_LOG = "info"
+/// GAZEBO_MODEL_PATH = { prepend = "share/my_package/models" }
+/// ```
+pub fn metadata_environment_hook(metadata: &Value) -> Result<Option<EnvironmentHook>> {
+    let variables = match metadata.get("ros").and_then(|ros| ros.get("environment")) {
+        Some(Value::Object(variables)) => variables,
+        Some(_) => bail!("The [package.metadata.ros.environment] entry is not a table"),
+        None => return Ok(None),
+    };
+    let mut operations = Vec::new();
+    for (variable, value) in variables {
+        operations.extend(parse_operations(variable, value).with_context(|| {
+            format!("Invalid [package.metadata.ros.environment] entry '{variable}'")
+        })?);
+    }
+    if operations.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(EnvironmentHook {
+        name: String::from("package_metadata"),
+        operations,
+    }))
+}
+
+fn parse_operations(variable: &str, value: &Value) -> Result<Vec<EnvironmentOperation>> {
+    let mut chars = variable.chars();
+    let valid_name = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid_name {
+        bail!("'{variable}' is not a valid variable name");
+    }
+    let variable = variable.to_owned();
+    let table = match value {
+        Value::String(value) => {
+            return Ok(vec![EnvironmentOperation::Set {
+                variable,
+                value: value.clone(),
+            }])
+        }
+        Value::Object(table) => table,
+        _ => bail!("The value must be a string or a table"),
+    };
+    let (operation, paths) = match table.iter().next() {
+        Some((operation, paths)) if table.len() == 1 => (operation.as_str(), paths),
+        _ => bail!("The table must contain exactly one of 'prepend', 'append' and 'set'"),
+    };
+    let paths = match paths {
+        Value::String(path) => vec![path.clone()],
+        Value::Array(paths) if operation != "set" => paths
+            .iter()
+            .map(|path| match path {
+                Value::String(path) => Ok(path.clone()),
+                _ => bail!("The paths must be strings"),
+            })
+            .collect::<Result<_>>()?,
+        _ => bail!("The value of '{operation}' must be a path"),
+    };
+    for path in &paths {
+        if !Path::new(path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        {
+            bail!("The path '{path}' must be relative to the install prefix");
+        }
+    }
+    paths
+        .into_iter()
+        .map(|path| {
+            let variable = variable.clone();
+            Ok(match operation {
+                "prepend" => EnvironmentOperation::PrependNonDuplicate { variable, path },
+                "append" => EnvironmentOperation::AppendNonDuplicate { variable, path },
+                "set" => EnvironmentOperation::SetPath { variable, path },
+                _ => {
+                    bail!("Unknown operation '{operation}', expected 'prepend', 'append' or 'set'")
+                }
+            })
+        })
+        .collect()
+}
+
+/// Write an environment hook as a `.dsv` file for colcon and a `.sh` file for ament's scripts
+pub fn install_environment_hook(
+    install_base: impl AsRef<Path>,
+    package_name: &str,
+    hook: &EnvironmentHook,
+    install_manifest: &mut InstallManifest,
+) -> Result<()> {
+    let environment_dir = install_base
+        .as_ref()
+        .join("share")
+        .join(package_name)
+        .join("environment");
+    std::fs::create_dir_all(&environment_dir)?;
+    let mut dsv = String::new();
+    let mut sh = String::from("# generated by cargo-ament-build\n\n");
+    for operation in &hook.operations {
+        dsv.push_str(&operation.dsv_line());
+        dsv.push('\n');
+        sh.push_str(&operation.sh_line());
+        sh.push('\n');
+    }
+    let dsv_path = environment_dir.join(format!("{}.dsv", hook.name));
+    install_contents(&dsv, &dsv_path, install_manifest)?;
+    let sh_path = environment_dir.join(format!("{}.sh", hook.name));
+    install_contents(&sh, &sh_path, install_manifest)
+}
+
+/// Write the given environment hooks and the package-level `local_setup` scripts
+///
+/// The `local_setup` scripts source all hooks that were installed for the package, including
+/// those installed before, e.g. by [`crate::install_files_from_metadata`].
+///
+/// The files are installed to `install_base`, which may be a staging directory, while `prefix`
+/// is the absolute path of the final install base. It is used by `local_setup.sh` when it is
+/// sourced directly, since a POSIX shell cannot determine the location of a sourced script.
+pub fn install_environment_hooks(
+    install_base: impl AsRef<Path>,
+    prefix: impl AsRef<Path>,
+    package_name: &str,
+    hooks: &[EnvironmentHook],
+    install_manifest: &mut InstallManifest,
+) -> Result<()> {
+    for hook in hooks {
+        install_environment_hook(&install_base, package_name, hook, install_manifest)?;
+    }
+    let share_dir = install_base.as_ref().join("share").join(package_name);
+    let environment_dir = Path::new("share").join(package_name).join("environment");
+    // The prefix hook comes first, like in ament_cmake packages
+    let mut hook_names: Vec<_> = install_manifest
+        .paths()
+        .filter(|path| path.parent() == Some(&environment_dir))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "dsv"))
+        .filter_map(|path| path.file_stem())
+        .map(|name| name.to_string_lossy().into_owned())
+        .collect();
+    hook_names.sort_by_key(|name| name != "ament_prefix_path");
+
+    let mut local_setup_dsv = String::new();
+    let mut sourced_hooks = String::new();
+    for name in hook_names {
+        local_setup_dsv.push_str(&format!(
+            "source;share/{package_name}/environment/{name}.dsv\n"
+        ));
+        sourced_hooks.push_str(&format!(
+            ". \"$AMENT_CURRENT_PREFIX/share/{package_name}/environment/{name}.sh\"\n"
+        ));
+    }
+
+    let prefix = sh_quote(&prefix.as_ref().to_string_lossy());
+    let local_setup_sh = format!(
+        r#"# generated by cargo-ament-build
+
+# since this file is sourced, use either the provided AMENT_CURRENT_PREFIX
+# or fall back to the install prefix at build time
+if [ -z "$AMENT_CURRENT_PREFIX" ]; then
+  _cargo_ament_build_set_prefix=1
+  AMENT_CURRENT_PREFIX={prefix}
+fi
+
+# functions to prepend or append a value to a variable, unless it is already
+# contained, which may already be defined by the setup script of the install space
+if ! command -v ament_prepend_unique_value > /dev/null 2>&1; then
+  _cargo_ament_build_defined_prepend=1
+  ament_prepend_unique_value() {{
+    eval _values=\"\$$1\"
+    case ":$_values:" in
+      *":$2:"*) ;;
+      "::") eval export $1=\"\$2\" ;;
+      *) eval export $1=\"\$2:\$_values\" ;;
+    esac
+    unset _values
+  }}
+fi
+if ! command -v ament_append_unique_value > /dev/null 2>&1; then
+  _cargo_ament_build_defined_append=1
+  ament_append_unique_value() {{
+    eval _values=\"\$$1\"
+    case ":$_values:" in
+      *":$2:"*) ;;
+      "::") eval export $1=\"\$2\" ;;
+      *) eval export $1=\"\$_values:\$2\" ;;
+    esac
+    unset _values
+  }}
+fi
+
+{sourced_hooks}
+if [ -n "$_cargo_ament_build_defined_prepend" ]; then
+  unset -f ament_prepend_unique_value
+fi
+if [ -n "$_cargo_ament_build_defined_append" ]; then
+  unset -f ament_append_unique_value
+fi
+unset _cargo_ament_build_defined_prepend _cargo_ament_build_defined_append
+if [ -n "$_cargo_ament_build_set_prefix" ]; then
+  unset AMENT_CURRENT_PREFIX
+fi
+unset _cargo_ament_build_set_prefix
+"#
+    );
+    let local_setup_bash = format!(
+        r#"# generated by cargo-ament-build
+
+# a bash script is able to determine its own location
+AMENT_CURRENT_PREFIX=$(builtin cd "`dirname "${{BASH_SOURCE[0]}}"`/../.." > /dev/null && pwd)
+. "$AMENT_CURRENT_PREFIX/share/{package_name}/local_setup.sh"
+unset AMENT_CURRENT_PREFIX
+"#
+    );
+    let local_setup_zsh = format!(
+        r#"# generated by cargo-ament-build
+
+# a zsh script is able to determine its own location
+AMENT_CURRENT_PREFIX=$(builtin cd -q "`dirname "${{(%):-%N}}"`/../.." > /dev/null && pwd)
+. "$AMENT_CURRENT_PREFIX/share/{package_name}/local_setup.sh"
+unset AMENT_CURRENT_PREFIX
+"#
+    );
+    for (extension, contents) in [
+        ("dsv", local_setup_dsv),
+        ("sh", local_setup_sh),
+        ("bash", local_setup_bash),
+        ("zsh", local_setup_zsh),
+    ] {
+        let path = share_dir.join(format!("local_setup.{extension}"));
+        install_contents(&contents, &path, install_manifest)?;
+    }
+    Ok(())
+}
+
+/// A path relative to the install prefix in a POSIX shell hook.
+fn sh_path(path: &str) -> String {
+    if path.is_empty() {
+        String::from("\"$AMENT_CURRENT_PREFIX\"")
+    } else {
+        format!("\"$AMENT_CURRENT_PREFIX\"/{}", sh_quote(path))
+    }
+}
+
+/// Quotes a value for a POSIX shell.
+fn sh_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_environment_hooks() {
+        let hooks = environment_hooks("pkg", true);
+        let names: Vec<_> = hooks.iter().map(|hook| hook.name.as_str()).collect();
+        assert_eq!(names, ["ament_prefix_path", "library_path"]);
+        assert_eq!(environment_hooks("pkg", false).len(), 1);
+    }
+
+    #[test]
+    fn test_metadata_environment_hook() -> Result<()> {
+        let metadata = serde_json::json!({
+            "ros": {
+                "environment": {
+                    "RUST_LOG": "info",
+                    "GAZEBO_MODEL_PATH": { "prepend": ["share/pkg/models", "share/pkg/worlds"] },
+                    "GZ_PLUGIN_PATH": { "append": "lib/pkg" },
+                    "PKG_CONFIG_FILE": { "set": "share/pkg/config.yaml" },
+                },
+            },
+        });
+
+        let hook = metadata_environment_hook(&metadata)?.unwrap();
+        let dsv: Vec<_> = hook.operations.iter().map(|op| op.dsv_line()).collect();
+        assert_eq!(
+            dsv,
+            [
+                "prepend-non-duplicate;GAZEBO_MODEL_PATH;share/pkg/models",
+                "prepend-non-duplicate;GAZEBO_MODEL_PATH;share/pkg/worlds",
+                "append-non-duplicate;GZ_PLUGIN_PATH;lib/pkg",
+                "set;PKG_CONFIG_FILE;share/pkg/config.yaml",
+                "set;RUST_LOG;info",
+            ]
+        );
+        assert_eq!(metadata_environment_hook(&Value::Null)?, None);
+
+        for invalid in [
+            serde_json::json!({ "NOT-VALID": "" }),
+            serde_json::json!({ "VAR": { "prepend": "/absolute" } }),
+            serde_json::json!({ "VAR": { "prepend": "../outside" } }),
+            serde_json::json!({ "VAR": { "set": ["a", "b"] } }),
+            serde_json::json!({ "VAR": { "prepend": "a", "append": "b" } }),
+            serde_json::json!({ "VAR": { "remove": "a" } }),
+            serde_json::json!({ "VAR": 1 }),
+        ] {
+            let metadata = serde_json::json!({ "ros": { "environment": invalid } });
+            assert!(metadata_environment_hook(&metadata).is_err());
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_local_setup_sh() -> Result<()> {
+        let tmp = tempdir()?;
+        let install_base = tmp.path().join("install's");
+        let metadata = serde_json::json!({
+            "ros": {
+                "environment": {
+                    "RUST_LOG": "debug",
+                    "GZ_PLUGIN_PATH": { "append": "lib/pkg" },
+                },
+            },
+        });
+        let mut install_manifest = InstallManifest::new(&install_base);
+        let hook = metadata_environment_hook(&metadata)?.unwrap();
+        install_environment_hook(&install_base, "pkg", &hook, &mut install_manifest)?;
+        install_environment_hooks(
+            &install_base,
+            &install_base,
+            "pkg",
+            &environment_hooks("pkg", true),
+            &mut install_manifest,
+        )?;
+
+        assert_eq!(
+            std::fs::read_to_string(install_base.join("share/pkg/environment/library_path.dsv"))?,
+            format!("prepend-non-duplicate;{LIBRARY_PATH_VARIABLE};lib/pkg\n")
+        );
+        assert_eq!(
+            std::fs::read_to_string(install_base.join("share/pkg/local_setup.dsv"))?,
+            "source;share/pkg/environment/ament_prefix_path.dsv\n\
+             source;share/pkg/environment/library_path.dsv\n\
+             source;share/pkg/environment/package_metadata.dsv\n"
+        );
+
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(format!(
+                ". {}; echo \"$AMENT_PREFIX_PATH|${LIBRARY_PATH_VARIABLE}|$GZ_PLUGIN_PATH|$RUST_LOG|$AMENT_CURRENT_PREFIX\"",
+                sh_quote(&install_base.join("share/pkg/local_setup.sh").to_string_lossy())
+            ))
+            .env("AMENT_PREFIX_PATH", "/opt/ros")
+            .env(LIBRARY_PATH_VARIABLE, "")
+            .env("GZ_PLUGIN_PATH", "/opt/gz")
+            .env_remove("AMENT_CURRENT_PREFIX")
+            .output()?;
+        assert_eq!(
+            String::from_utf8(output.stdout)?,
+            format!(
+                "{base}:/opt/ros|{base}/lib/pkg|/opt/gz:{base}/lib/pkg|debug|\n",
+                base = install_base.display()
+            )
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,245 @@
+// Licensed under the Apache License, Version 2.0
+
+use cargo_metadata::Package;
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+
+/// The feature flags given to cargo.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FeatureSelection {
+    /// The values of all `--features`/`-F` options, split into single features
+    pub features: Vec<String>,
+    /// Whether `--all-features` was given
+    pub all_features: bool,
+    /// Whether `--no-default-features` was given
+    pub no_default_features: bool,
+}
+
+impl FeatureSelection {
+    /// Extracts the feature flags from arguments for cargo.
+    ///
+    /// Like cargo, this accepts `--features a,b`, `--features="a b"`, `-F a` and `-Fa`.
+    pub fn from_args(args: &[OsString]) -> Self {
+        let mut selection = Self::default();
+        let mut args = args.iter().map(|arg| arg.to_string_lossy());
+        while let Some(arg) = args.next() {
+            let value = match arg.as_ref() {
+                "--all-features" => {
+                    selection.all_features = true;
+                    continue;
+                }
+                "--no-default-features" => {
+                    selection.no_default_features = true;
+                    continue;
+                }
+                "--features" | "-F" => args.next(),
+                _ => arg
+                    .strip_prefix("--features=")
+                    .or_else(|| arg.strip_prefix("-F"))
+                    .map(|value| value.to_owned().into()),
+            };
+            if let Some(value) = value {
+                selection.features.extend(
+                    value
+                        .split(|c: char| c == ',' || c.is_whitespace())
+                        .filter(|feature| !feature.is_empty())
+                        .map(String::from),
+                );
+            }
+        }
+        selection
+    }
+
+    /// Turns the selection back into arguments for cargo, e.g. for a `cargo doc` that documents
+    /// the same features as the build.
+    pub fn to_args(&self) -> Vec<OsString> {
+        let mut args = Vec::new();
+        if !self.features.is_empty() {
+            args.push("--features".into());
+            args.push(self.features.join(",").into());
+        }
+        if self.all_features {
+            args.push("--all-features".into());
+        }
+        if self.no_default_features {
+            args.push("--no-default-features".into());
+        }
+        args
+    }
+
+    /// Computes the features of a package that cargo activates for this selection.
+    ///
+    /// Features enabled by other features are included, as well as `dep/feature` entries for
+    /// features of dependencies, since those can appear in `required-features` too.
+    /// `workspace_members` are the names of the packages in the workspace, whose features are
+    /// selected as `package/feature` as well.
+    pub fn resolve(&self, package: &Package, workspace_members: &[&str]) -> HashSet<String> {
+        let mut pending: Vec<&str> = Vec::new();
+        if self.all_features {
+            pending.extend(package.features.keys().map(String::as_str));
+        }
+        if !self.no_default_features {
+            pending.push("default");
+        }
+        for feature in &self.features {
+            match feature.split_once('/') {
+                // `package/feature` is how features are selected in a workspace
+                Some((package_name, feature)) if package_name == package.name => {
+                    pending.push(feature)
+                }
+                // A feature of another package of the workspace, not of a dependency
+                Some((package_name, _)) if workspace_members.contains(&package_name) => {}
+                _ => pending.push(feature),
+            }
+        }
+
+        let mut active = HashSet::new();
+        let mut weak = Vec::new();
+        while let Some(feature) = pending.pop() {
+            if active.contains(feature) {
+                continue;
+            }
+            if let Some((dependency, _)) = feature.split_once('/') {
+                if dependency.ends_with('?') {
+                    weak.push(feature);
+                } else {
+                    active.insert(feature.to_owned());
+                    // Enables the implicit feature of an optional dependency, if there is one
+                    pending.push(dependency);
+                }
+                continue;
+            }
+            // Unknown features are rejected by cargo itself
+            let Some(enabled) = package.features.get(feature) else {
+                continue;
+            };
+            active.insert(feature.to_owned());
+            pending.extend(
+                enabled
+                    .iter()
+                    .map(String::as_str)
+                    .filter(|entry| !entry.starts_with("dep:")),
+            );
+        }
+        // A weak dependency feature (`dep?/feature`) only applies if the dependency is enabled
+        // by something else
+        for feature in weak {
+            let feature = feature.replacen('?', "", 1);
+            // unwrap is ok since only features containing a '/' are weak
+            let (dependency, _) = feature.split_once('/').unwrap();
+            if active.contains(dependency) {
+                active.insert(feature);
+            }
+        }
+        active
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::package;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    fn sorted(features: HashSet<String>) -> Vec<String> {
+        let mut features: Vec<_> = features.into_iter().collect();
+        features.sort();
+        features
+    }
+
+    #[test]
+    fn test_from_args() {
+        let selection = FeatureSelection::from_args(&args(&[
+            "--release",
+            "--features",
+            "a,b",
+            "--features=c d",
+            "-F",
+            "e",
+            "-Ff",
+            "--no-default-features",
+        ]));
+
+        assert_eq!(selection.features, ["a", "b", "c", "d", "e", "f"]);
+        assert!(selection.no_default_features);
+        assert!(!selection.all_features);
+
+        // The arguments select the same features again
+        assert_eq!(FeatureSelection::from_args(&selection.to_args()), selection);
+    }
+
+    #[test]
+    fn test_resolve_defaults_and_transitive_features() {
+        let package = package(serde_json::json!({
+            "features": {
+                "default": ["std"],
+                "std": ["alloc"],
+                "alloc": [],
+                "serde": ["dep:serde", "rclrs/serde"],
+                "extra": ["rclrs?/extra"],
+                "unused": [],
+            },
+        }));
+
+        let default = FeatureSelection::default().resolve(&package, &[]);
+        assert_eq!(sorted(default), ["alloc", "default", "std"]);
+
+        let selection = FeatureSelection::from_args(&args(&[
+            "--no-default-features",
+            "-F",
+            "my_package/serde,extra",
+        ]));
+        assert_eq!(
+            sorted(selection.resolve(&package, &[])),
+            ["extra", "rclrs/serde", "serde"]
+        );
+
+        // Features of other packages of the workspace are not features of dependencies
+        let selection = FeatureSelection::from_args(&args(&[
+            "--no-default-features",
+            "--features",
+            "other_package/rclrs,my_package/std",
+        ]));
+        assert_eq!(
+            sorted(selection.resolve(&package, &["my_package", "other_package"])),
+            ["alloc", "std"]
+        );
+
+        let selection = FeatureSelection::from_args(&args(&["--all-features"]));
+        assert_eq!(
+            sorted(selection.resolve(&package, &[])),
+            [
+                "alloc",
+                "default",
+                "extra",
+                "rclrs/serde",
+                "serde",
+                "std",
+                "unused"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_optional_dependency() {
+        let package = package(serde_json::json!({
+            "features": {
+                "rclrs": ["dep:rclrs"],
+                "ros": ["rclrs/dyn_msg", "logging?/ros"],
+                "logging": ["dep:logging"],
+            },
+        }));
+
+        let selection = FeatureSelection::from_args(&args(&["--features", "ros"]));
+
+        // The implicit feature of the optional dependency is enabled, but not the weak one
+        assert_eq!(
+            sorted(selection.resolve(&package, &[])),
+            ["rclrs", "rclrs/dyn_msg", "ros"]
+        );
+    }
+}
@@ -0,0 +1,173 @@
+// Licensed under the Apache License, Version 2.0
+
+use anyhow::Result;
+use cargo_metadata::Package;
+use serde_json::{json, Value};
+
+use crate::{
+    expected_binaries, includes_ros_env, metadata_install_entries, DependencyType, PackageXml,
+    TargetSelection,
+};
+
+use std::collections::HashSet;
+use std::path::Path;
+
+/// Describes what the plugin does when building and installing a package, as JSON.
+///
+/// This is meant for integrations like colcon-ros-cargo, so that they do not need to interpret
+/// the `Cargo.toml` and `package.xml` themselves. The install base is null if it is not known.
+pub fn package_info(
+    package: &Package,
+    features: &HashSet<String>,
+    targets: &TargetSelection,
+    install_base: Option<&Path>,
+    install_docs: bool,
+) -> Result<Value> {
+    let package_xml_path = package.manifest_path.with_file_name("package.xml");
+    let package_xml = if package_xml_path.exists() {
+        Some(PackageXml::read(&package_xml_path)?)
+    } else {
+        None
+    };
+    let binaries: Vec<_> = expected_binaries(package, features, targets)
+        .iter()
+        .map(|target| target.name.clone())
+        .collect();
+    // These are the libraries whose files are installed, in addition to the binaries
+    let libraries: Vec<_> = package
+        .targets
+        .iter()
+        .filter(|target| target.is_cdylib() || target.is_staticlib())
+        .map(|target| json!({ "name": target.name, "crate_types": target.crate_types }))
+        .collect();
+    let install_entries: serde_json::Map<_, _> = metadata_install_entries(&package.metadata)?
+        .into_iter()
+        .map(|(subdir, entries)| (format!("install_to_{subdir}"), json!(entries)))
+        .collect();
+
+    let mut resources = vec!["packages".to_owned()];
+    if !includes_ros_env(package) {
+        resources.push("rust_packages".to_owned());
+    }
+    if package_xml.is_some() {
+        resources.push("package_run_dependencies".to_owned());
+        resources.push("parent_prefix_path".to_owned());
+    }
+    if install_docs {
+        resources.push("rust_docs".to_owned());
+    }
+    if let Some(Value::Object(metadata_resources)) = package
+        .metadata
+        .get("ros")
+        .and_then(|ros| ros.get("resources"))
+    {
+        resources.extend(metadata_resources.keys().cloned());
+    }
+
+    let dependencies = package_xml.as_ref().map(|package_xml| {
+        let names = |dependency_types: &[DependencyType]| {
+            let mut names: Vec<&str> = Vec::new();
+            for &dependency_type in dependency_types {
+                for name in package_xml.dependencies(dependency_type) {
+                    if !names.contains(&name) {
+                        names.push(name);
+                    }
+                }
+            }
+            names
+        };
+        json!({
+            "build": names(&[DependencyType::Build, DependencyType::Buildtool]),
+            "run": package_xml.run_dependencies(),
+            "test": names(&[DependencyType::Test]),
+        })
+    });
+
+    Ok(json!({
+        "name": package.name,
+        "version": package.version.to_string(),
+        "manifest_path": package.manifest_path,
+        "install_base": install_base,
+        "build_type": package_xml.as_ref().and_then(|package_xml| package_xml.build_type.clone()),
+        "dependencies": dependencies,
+        "binaries": binaries,
+        "libraries": libraries,
+        "install_entries": install_entries,
+        "ros_env_include": includes_ros_env(package),
+        "install_docs": install_docs,
+        "resources": resources,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::package;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_package_info() -> Result<()> {
+        let tmp = tempdir()?;
+        let manifest_path = tmp.path().join("Cargo.toml");
+        std::fs::write(
+            tmp.path().join("package.xml"),
+            r#"<package format="3">
+              <name>my_package</name>
+              <version>0.1.0</version>
+              <buildtool_depend>ament_cargo</buildtool_depend>
+              <depend>rclrs</depend>
+              <test_depend>test_msgs</test_depend>
+              <export><build_type>ament_cargo</build_type></export>
+            </package>"#,
+        )?;
+        let package = package(json!({
+            "targets": [
+                { "name": "talker", "kind": ["bin"], "crate_types": ["bin"],
+                  "src_path": "/src/bin/talker.rs", "required-features": ["talk"] },
+                { "name": "listener", "kind": ["bin"], "crate_types": ["bin"],
+                  "src_path": "/src/bin/listener.rs" },
+                { "name": "my_package", "kind": ["lib", "cdylib"],
+                  "crate_types": ["lib", "cdylib"], "src_path": "/src/lib.rs" },
+            ],
+            "features": { "talk": [] },
+            "manifest_path": manifest_path,
+            "metadata": {
+                "ros": {
+                    "install_to_share": ["launch"],
+                    "resources": { "my_resource": "content" },
+                },
+            },
+        }));
+
+        let info = package_info(
+            &package,
+            &HashSet::new(),
+            &TargetSelection::default(),
+            None,
+            false,
+        )?;
+        assert_eq!(info["build_type"], "ament_cargo");
+        assert_eq!(info["install_base"], Value::Null);
+        assert_eq!(info["binaries"], json!(["listener"]));
+        assert_eq!(info["libraries"][0]["name"], "my_package");
+        assert_eq!(
+            info["install_entries"],
+            json!({ "install_to_share": ["launch"] })
+        );
+        assert_eq!(
+            info["dependencies"],
+            json!({ "build": ["rclrs", "ament_cargo"], "run": ["rclrs"], "test": ["test_msgs"] })
+        );
+        assert_eq!(
+            info["resources"],
+            json!([
+                "packages",
+                "rust_packages",
+                "package_run_dependencies",
+                "parent_prefix_path",
+                "my_resource"
+            ])
+        );
+        Ok(())
+    }
+}
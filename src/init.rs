@@ -0,0 +1,244 @@
+// Licensed under the Apache License, Version 2.0
+
+use anyhow::{bail, Context, Result};
+
+use std::fmt::Write;
+use std::path::{Component, Path, PathBuf};
+
+/// Whether a new package contains a node or a library.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PackageKind {
+    /// A binary with the name of the package, which can be run with `ros2 run`
+    #[default]
+    Node,
+    /// A library for other Rust packages
+    Library,
+}
+
+/// The options of the `init` subcommand.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InitOptions {
+    /// The name of both the Cargo and the ROS package
+    pub name: String,
+    /// The directory to create the package in, by default a directory named like the package
+    pub path: Option<PathBuf>,
+    pub kind: PackageKind,
+    /// Directories of the package that are installed to `share/<package>`, e.g. `launch`
+    pub share_dirs: Vec<String>,
+    /// ROS packages that the package depends on, e.g. `rclrs`
+    pub dependencies: Vec<String>,
+    /// The SPDX license identifier
+    pub license: Option<String>,
+}
+
+impl InitOptions {
+    /// The directory that the package is created in.
+    pub fn package_path(&self) -> PathBuf {
+        self.path
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(&self.name))
+    }
+}
+
+/// Creates a new package with a `Cargo.toml` and `package.xml` that match each other.
+///
+/// The ROS dependencies are added to both files, with a `*` version requirement in the
+/// `Cargo.toml` since colcon-ros-cargo patches them to the packages in the install space. An
+/// existing package is never overwritten.
+pub fn init_package(options: &InitOptions) -> Result<()> {
+    for name in std::iter::once(&options.name).chain(&options.dependencies) {
+        if !is_valid_package_name(name) {
+            bail!(
+                "'{name}' is not a valid ROS package name, it must start with a lowercase letter \
+                 and only contain lowercase letters, digits and underscores (REP 144)"
+            );
+        }
+    }
+    for dir in &options.share_dirs {
+        let is_relative = Path::new(dir)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if dir.is_empty() || !is_relative {
+            bail!("The share directory '{dir}' must be a relative path inside the package");
+        }
+    }
+    let package_path = options.package_path();
+    for file in ["Cargo.toml", "package.xml"] {
+        if package_path.join(file).exists() {
+            bail!(
+                "'{}' already exists, refusing to overwrite it",
+                package_path.join(file).display()
+            );
+        }
+    }
+
+    let (source_file, source) = match options.kind {
+        PackageKind::Node => (
+            "main.rs",
+            format!("fn main() {{\n    println!(\"Hello from {}!\");\n}}\n", options.name),
+        ),
+        PackageKind::Library => (
+            "lib.rs",
+            "/// Adds two numbers.\npub fn add(left: u64, right: u64) -> u64 {\n    left + right\n}\n"
+                .to_owned(),
+        ),
+    };
+    let files = [
+        (PathBuf::from("Cargo.toml"), cargo_toml(options)),
+        (PathBuf::from("package.xml"), package_xml(options)),
+        (Path::new("src").join(source_file), source),
+    ];
+    for (file, contents) in files {
+        let path = package_path.join(file);
+        // unwrap is ok since the path is inside the package directory
+        std::fs::create_dir_all(path.parent().unwrap())
+            .and_then(|()| std::fs::write(&path, contents))
+            .with_context(|| format!("Failed to write '{}'", path.display()))?;
+    }
+    // install_to_share fails for directories that do not exist
+    for dir in &options.share_dirs {
+        let path = package_path.join(dir);
+        std::fs::create_dir_all(&path)
+            .with_context(|| format!("Failed to create '{}'", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Whether a name follows the naming rules of REP 144 for ROS packages.
+fn is_valid_package_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_lowercase())
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+fn cargo_toml(options: &InitOptions) -> String {
+    let mut cargo_toml = format!(
+        "[package]\nname = \"{}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+        options.name
+    );
+    if let Some(license) = &options.license {
+        // unwrap is ok since writing to a string cannot fail
+        writeln!(cargo_toml, "license = {}", toml_edit::value(license)).unwrap();
+    }
+    cargo_toml.push_str("\n[dependencies]\n");
+    for dependency in &options.dependencies {
+        writeln!(cargo_toml, "{dependency} = \"*\"").unwrap();
+    }
+    if !options.share_dirs.is_empty() {
+        let share_dirs: toml_edit::Array = options.share_dirs.iter().collect();
+        writeln!(
+            cargo_toml,
+            "\n[package.metadata.ros]\ninstall_to_share = {share_dirs}"
+        )
+        .unwrap();
+    }
+    cargo_toml
+}
+
+pub(crate) fn package_xml(options: &InitOptions) -> String {
+    // Like `ros2 pkg create`, the maintainer is the current user
+    let user = std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "maintainer".to_owned());
+    let user = xml_escape(&user);
+    let license = xml_escape(
+        options
+            .license
+            .as_deref()
+            .unwrap_or("TODO: License declaration"),
+    );
+    let mut package_xml = format!(
+        r#"<?xml version="1.0"?>
+<?xml-model href="http://download.ros.org/schema/package_format3.xsd" schematypens="http://www.w3.org/2001/XMLSchema"?>
+<package format="3">
+  <name>{name}</name>
+  <version>0.1.0</version>
+  <description>TODO: Package description</description>
+  <maintainer email="{user}@todo.todo">{user}</maintainer>
+  <license>{license}</license>
+
+"#,
+        name = options.name
+    );
+    for dependency in &options.dependencies {
+        writeln!(package_xml, "  <depend>{dependency}</depend>").unwrap();
+    }
+    if !options.dependencies.is_empty() {
+        package_xml.push('\n');
+    }
+    package_xml.push_str(
+        "  <export>\n    <build_type>ament_cargo</build_type>\n  </export>\n</package>\n",
+    );
+    package_xml
+}
+
+pub(crate) fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PackageXml;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_init_package() -> Result<()> {
+        let tmp = tempdir()?;
+        let options = InitOptions {
+            name: "my_node".to_owned(),
+            path: Some(tmp.path().join("my_node")),
+            kind: PackageKind::Node,
+            share_dirs: vec!["launch".to_owned(), "config".to_owned()],
+            dependencies: vec!["rclrs".to_owned(), "std_msgs".to_owned()],
+            license: Some("Apache-2.0".to_owned()),
+        };
+        init_package(&options)?;
+
+        let package_path = tmp.path().join("my_node");
+        assert!(package_path.join("src/main.rs").is_file());
+        assert!(package_path.join("launch").is_dir());
+        let cargo_toml: toml_edit::DocumentMut =
+            std::fs::read_to_string(package_path.join("Cargo.toml"))?.parse()?;
+        assert_eq!(cargo_toml["package"]["name"].as_str(), Some("my_node"));
+        assert_eq!(cargo_toml["dependencies"]["rclrs"].as_str(), Some("*"));
+        assert_eq!(
+            cargo_toml["package"]["metadata"]["ros"]["install_to_share"]
+                .as_array()
+                .map(|array| array.len()),
+            Some(2)
+        );
+        let package_xml = PackageXml::read(package_path.join("package.xml"))?;
+        assert_eq!(package_xml.name, "my_node");
+        assert_eq!(package_xml.build_type.as_deref(), Some("ament_cargo"));
+        assert_eq!(package_xml.run_dependencies(), ["rclrs", "std_msgs"]);
+
+        // An existing package is not overwritten
+        assert!(init_package(&options).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_init_rejects_invalid_names() {
+        let tmp = tempdir().unwrap();
+        for name in ["MyNode", "my-node", "1node", ""] {
+            let options = InitOptions {
+                name: name.to_owned(),
+                path: Some(tmp.path().join("pkg")),
+                ..Default::default()
+            };
+            assert!(init_package(&options).is_err(), "{name}");
+        }
+        let options = InitOptions {
+            name: "my_node".to_owned(),
+            path: Some(tmp.path().join("pkg")),
+            share_dirs: vec!["../outside".to_owned()],
+            ..Default::default()
+        };
+        assert!(init_package(&options).is_err());
+        assert!(!tmp.path().join("pkg").exists());
+    }
+}
@@ -0,0 +1,255 @@
+// Licensed under the Apache License, Version 2.0
+
+use anyhow::{bail, Context, Result};
+
+use std::collections::BTreeSet;
+use std::path::{Component, Path, PathBuf};
+
+/// The files and symlinks that were installed for a package, like CMake's `install_manifest.txt`
+///
+/// Paths are stored relative to the install base of the package, one per line, so that
+/// uninstalling can never remove anything outside of it.
+///
+/// When installing to a staging directory, the manifest also knows the install base that the
+/// files end up in, so that unchanged files can be left there instead of being installed again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstallManifest {
+    /// The directory that files are installed to, which is the install base unless staging
+    root: PathBuf,
+    install_base: PathBuf,
+    paths: BTreeSet<PathBuf>,
+    /// The paths that are already up to date in the install base
+    unchanged: BTreeSet<PathBuf>,
+    /// The paths of the previous install of the package
+    previous: BTreeSet<PathBuf>,
+    compare_contents: bool,
+}
+
+impl InstallManifest {
+    /// Creates an empty manifest for the given install base.
+    pub fn new(install_base: impl AsRef<Path>) -> Self {
+        Self::staged(&install_base, &install_base, false)
+    }
+
+    /// Creates an empty manifest for files that are installed to `staging_dir` and later moved
+    /// to `install_base`.
+    ///
+    /// Files count as unchanged if their size and modification time match, or, with
+    /// `compare_contents`, also if their contents are equal.
+    pub fn staged(
+        staging_dir: impl AsRef<Path>,
+        install_base: impl AsRef<Path>,
+        compare_contents: bool,
+    ) -> Self {
+        Self {
+            root: staging_dir.as_ref().to_owned(),
+            install_base: install_base.as_ref().to_owned(),
+            paths: BTreeSet::new(),
+            unchanged: BTreeSet::new(),
+            previous: BTreeSet::new(),
+            compare_contents,
+        }
+    }
+
+    /// The location of the manifest of a package in the build base.
+    pub fn location(build_base: impl AsRef<Path>, package_name: &str) -> PathBuf {
+        build_base
+            .as_ref()
+            .join("ament_build")
+            .join(package_name)
+            .join("install_manifest.txt")
+    }
+
+    /// Reads a manifest written by [`InstallManifest::write`].
+    ///
+    /// A missing manifest is treated as empty, since the package may not have been installed yet.
+    pub fn read(install_base: impl AsRef<Path>, path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut manifest = Self::new(install_base);
+        if !path.exists() {
+            return Ok(manifest);
+        }
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read install manifest '{}'", path.display()))?;
+        for line in content.lines().filter(|line| !line.is_empty()) {
+            let entry = Path::new(line);
+            if !entry
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+            {
+                bail!(
+                    "Install manifest '{}' contains the path '{line}', which is not inside the install base",
+                    path.display()
+                );
+            }
+            manifest.paths.insert(entry.to_owned());
+        }
+        Ok(manifest)
+    }
+
+    /// Records a file, symlink or empty directory that was installed below the install base.
+    pub fn record(&mut self, path: impl AsRef<Path>) {
+        let relative = self.relative(path.as_ref());
+        self.paths.insert(relative);
+    }
+
+    /// Records a file, symlink or empty directory that was not installed because it is already up
+    /// to date.
+    pub fn record_unchanged(&mut self, path: impl AsRef<Path>) {
+        let relative = self.relative(path.as_ref());
+        self.paths.insert(relative.clone());
+        self.unchanged.insert(relative);
+    }
+
+    /// Whether the file, symlink or empty directory was recorded.
+    pub fn contains(&self, relative: impl AsRef<Path>) -> bool {
+        self.paths.contains(relative.as_ref())
+    }
+
+    /// Whether the path was recorded with [`InstallManifest::record_unchanged`].
+    pub fn is_unchanged(&self, relative: impl AsRef<Path>) -> bool {
+        self.unchanged.contains(relative.as_ref())
+    }
+
+    /// Where a path below the directory that files are installed to ends up in the install base.
+    pub fn installed_path(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.install_base.join(self.relative(path.as_ref()))
+    }
+
+    /// Remembers the manifest of the previous install of the package, whose files can be replaced
+    /// without conflicting with other packages.
+    pub fn set_previous_install(&mut self, previous: &InstallManifest) {
+        self.previous = previous.paths.clone();
+    }
+
+    /// Whether the file or symlink was part of the previous install of the package.
+    pub fn was_installed_before(&self, path: impl AsRef<Path>) -> bool {
+        self.previous.contains(&self.relative(path.as_ref()))
+    }
+
+    /// Whether unchanged files are detected by comparing their contents.
+    pub fn compares_contents(&self) -> bool {
+        self.compare_contents
+    }
+
+    fn relative(&self, path: &Path) -> PathBuf {
+        path.strip_prefix(&self.root).unwrap_or(path).to_owned()
+    }
+
+    /// Adds the paths recorded in another manifest, e.g. the one of a staged install.
+    ///
+    /// The paths are taken as they are, without regard to whether they were unchanged.
+    pub fn extend(&mut self, other: &InstallManifest) {
+        self.paths.extend(other.paths.iter().cloned());
+    }
+
+    /// The recorded paths, relative to the install base.
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.paths.iter().map(PathBuf::as_path)
+    }
+
+    /// Writes the manifest, creating the directory containing it if necessary.
+    ///
+    /// Entries whose files no longer exist, e.g. because a directory was reinstalled, are dropped.
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut content = String::new();
+        for entry in &self.paths {
+            if self.install_base.join(entry).symlink_metadata().is_ok() {
+                content.push_str(&entry.to_string_lossy());
+                content.push('\n');
+            }
+        }
+        std::fs::write(path, content)
+            .with_context(|| format!("Failed to write install manifest '{}'", path.display()))
+    }
+
+    /// Removes all recorded files, symlinks and empty directories, and the directories that
+    /// became empty.
+    ///
+    /// Directories are pruned up to, but not including, the install base.
+    pub fn uninstall(&self) -> Result<()> {
+        for entry in &self.paths {
+            let path = self.install_base.join(entry);
+            if path.symlink_metadata().is_err() {
+                continue;
+            }
+            if path.is_dir() && !path.is_symlink() {
+                // The directory is kept if something else was put into it
+                if std::fs::remove_dir(&path).is_err() {
+                    continue;
+                }
+            } else {
+                std::fs::remove_file(&path)
+                    .with_context(|| format!("Failed to remove '{}'", path.display()))?;
+            }
+            for dir in path.ancestors().skip(1) {
+                if dir == self.install_base || std::fs::remove_dir(dir).is_err() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_write_read_and_uninstall() -> Result<()> {
+        let tmp = tempdir()?;
+        let install_base = tmp.path().join("install");
+        let manifest_path = InstallManifest::location(tmp.path().join("build"), "pkg");
+        let marker = install_base.join("share/ament_index/resource_index/packages/pkg");
+        let other_marker = install_base.join("share/ament_index/resource_index/packages/other");
+        let binary = install_base.join("lib/pkg/node");
+        for path in [&marker, &other_marker, &binary] {
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::write(path, "")?;
+        }
+
+        let mut manifest = InstallManifest::new(&install_base);
+        manifest.record(&marker);
+        manifest.record(&binary);
+        manifest.record(install_base.join("share/pkg/removed.py"));
+        manifest.write(&manifest_path)?;
+
+        let manifest = InstallManifest::read(&install_base, &manifest_path)?;
+        assert_eq!(
+            manifest.paths().collect::<Vec<_>>(),
+            [
+                Path::new("lib/pkg/node"),
+                Path::new("share/ament_index/resource_index/packages/pkg")
+            ]
+        );
+
+        manifest.uninstall()?;
+        assert!(!marker.exists());
+        assert!(!install_base.join("lib").exists());
+        // Files of other packages and the directories containing them are kept
+        assert!(other_marker.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_rejects_paths_outside_install_base() -> Result<()> {
+        let tmp = tempdir()?;
+        let manifest_path = tmp.path().join("install_manifest.txt");
+        std::fs::write(&manifest_path, "share/pkg/package.xml\n../outside\n")?;
+
+        assert!(InstallManifest::read(tmp.path().join("install"), &manifest_path).is_err());
+        assert!(
+            InstallManifest::read(tmp.path().join("install"), tmp.path().join("missing"))?
+                .paths()
+                .next()
+                .is_none()
+        );
+        Ok(())
+    }
+}
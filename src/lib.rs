@@ -1,36 +1,126 @@
 // Licensed under the Apache License, Version 2.0
 
 use anyhow::{anyhow, bail, Context, Result};
-use cargo_manifest::{Manifest, Product, StringOrBool, Value};
+use cargo_metadata::camino::Utf8Path;
+use cargo_metadata::{Artifact, Message, Metadata, MetadataCommand, Package, Target, TargetKind};
+use serde_json::Value;
 
 use std::collections::HashSet;
 use std::ffi::OsString;
 use std::fs::{DirBuilder, File};
+use std::io::{BufRead, BufReader};
 use std::path::Path;
 use std::path::PathBuf;
-use std::process::Command;
+use std::process::{Command, Stdio};
+
+mod coverage;
+mod domain_id;
+mod environment;
+mod features;
+mod info;
+mod init;
+mod install_manifest;
+mod lint;
+mod manifest;
+mod package_xml;
+mod staging;
+mod sync_package_xml;
+mod targets;
+mod test_results;
+#[cfg(test)]
+mod test_util;
+
+pub use coverage::{
+    cobertura_xml, coverage_args, parse_lcov, prepare_coverage, write_coverage_reports,
+    FileCoverage,
+};
+pub use domain_id::DomainIdLock;
+pub use environment::{
+    environment_hooks, install_environment_hook, install_environment_hooks,
+    metadata_environment_hook, EnvironmentHook, EnvironmentOperation,
+};
+pub use features::FeatureSelection;
+pub use info::package_info;
+pub use init::{init_package, InitOptions, PackageKind};
+pub use install_manifest::InstallManifest;
+pub use lint::{
+    cargo_clippy, cargo_fmt_check, lint_suites, parse_rustfmt_diffs, ClippyOutput,
+    FormatCheckOutput, FormatDiff, LintConfig,
+};
+pub use package_xml::{Dependency, DependencyType, PackageXml};
+pub use staging::StagedInstall;
+pub use sync_package_xml::{sync_package_xml, sync_package_xml_file};
+pub use targets::TargetSelection;
+pub use test_results::{
+    parse_test_output, run_doc_tests, run_test_executable, write_junit, TestCase, TestOutcome,
+    TestRun, TestSuite,
+};
+
+/// The action performed by the plugin.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Subcommand {
+    /// Build the packages and install them
+    #[default]
+    Build,
+    /// Remove the files recorded in the install manifests of the packages
+    Uninstall,
+    /// Update the package.xml of the packages from their Cargo.toml
+    SyncPackageXml,
+    /// Print what building and installing the packages does, as JSON
+    Info,
+    /// Run the tests of the packages and write their results as JUnit XML
+    Test,
+    /// Run clippy and rustfmt on the packages and write their findings as JUnit XML
+    Lint,
+}
 
 /// Arguments for both the wrapper and for `cargo build`.
 pub struct Args {
+    /// What to do with the selected packages
+    pub subcommand: Subcommand,
     /// The install base for this package (i.e. directory containing `lib`, `share` etc.)
     pub install_base: PathBuf,
     /// The build base for this package, corresponding to the --target-dir option
     pub build_base: PathBuf,
     /// Arguments to be forwarded to `cargo build`.
     pub forwarded_args: Vec<OsString>,
-    /// "debug", "release" etc.
-    pub profile: String,
     /// The target architecture. None for native builds.
     pub arch: Option<String>,
-    /// The absolute path to the Cargo.toml file. Currently the --manifest-path option is not implemented.
+    /// The absolute path to the Cargo.toml file.
     pub manifest_path: PathBuf,
-    /// Features that were active when compiling
-    pub features: HashSet<String>,
+    /// The feature flags among the arguments forwarded to cargo
+    pub features: FeatureSelection,
+    /// The package and target selection flags among the arguments forwarded to cargo
+    pub targets: TargetSelection,
+    /// Whether packages of a workspace are all installed to the install base, instead of each
+    /// one to its own prefix below it
+    pub merge_install: bool,
+    /// Whether files from the source tree are copied or symlinked
+    pub install_mode: InstallMode,
+    /// Whether files whose modification time changed are compared by their contents before
+    /// installing them again
+    pub compare_contents: bool,
+    /// Whether the dependencies in the package.xml are compared with the Cargo dependencies
+    pub lint_deps: bool,
+    /// Whether a package.xml that does not match the Cargo.toml fails the build, instead of
+    /// only printing warnings
+    pub strict_package_xml: bool,
+    /// Whether tests only communicate with ROS nodes on the same machine
+    pub localhost_only: bool,
+    /// Whether the code coverage of the tests is collected
+    pub coverage: bool,
+    /// Whether the documentation of all packages is built and installed, in addition to the
+    /// packages that enable `install_docs`
+    pub doc: bool,
 }
 
-/// Wrapper around [`Args`] that can also indicate the --help flag.
+/// Wrapper around [`Args`] that can also indicate the --help flag, or the `init` subcommand,
+/// which takes entirely different arguments.
+// There is only ever one instance, so its size does not matter.
+#[allow(clippy::large_enum_variant)]
 pub enum ArgsOrHelp {
     Args(Args),
+    Init(InitOptions),
     Help,
 }
 
@@ -57,14 +147,21 @@ impl ArgsOrHelp {
         if args.contains("--help") {
             return Ok(ArgsOrHelp::Help);
         }
-        let profile = if args.contains("--release") {
-            String::from("release")
-        } else if let Ok(p) = args.value_from_str("--profile") {
-            p
-        } else {
-            String::from("debug")
+        let mut subcommand = args.subcommand()?;
+        // When run as `cargo ament-build`, cargo passes the name of the plugin first
+        if subcommand.as_deref() == Some("ament-build") {
+            subcommand = args.subcommand()?;
+        }
+        let subcommand = match subcommand.as_deref() {
+            None => Subcommand::Build,
+            Some("uninstall") => Subcommand::Uninstall,
+            Some("sync-package-xml") => Subcommand::SyncPackageXml,
+            Some("info") => Subcommand::Info,
+            Some("test") => Subcommand::Test,
+            Some("lint") => Subcommand::Lint,
+            Some("init") => return Self::parse_init(args).map(ArgsOrHelp::Init),
+            Some(other) => bail!("Unknown subcommand '{other}'"),
         };
-
         let arch = args
             .opt_value_from_str("--target")?
             .or_else(|| std::env::var("CARGO_BUILD_TARGET").ok());
@@ -72,63 +169,395 @@ impl ArgsOrHelp {
         let build_base = args
             .opt_value_from_str("--target-dir")?
             .unwrap_or_else(|| "target".into());
-        let install_base = args.value_from_str("--install-base")?;
-
-        let manifest_path = if let Ok(p) = args.value_from_str("--manifest-path") {
-            p
-        } else {
-            PathBuf::from("Cargo.toml")
-                .canonicalize()
-                .context("Package manifest does not exist")?
+        // Only building and uninstalling use the install base, the other subcommands can use it
+        // if it is given
+        let install_base = match args.opt_value_from_str("--install-base")? {
+            Some(install_base) => install_base,
+            None if !matches!(subcommand, Subcommand::Build | Subcommand::Uninstall) => {
+                PathBuf::new()
+            }
+            None => bail!("The --install-base option is required"),
         };
-
-        let features = match args.values_from_str("--features") {
-            Ok(features) => features,
-            Err(pico_args::Error::MissingArgument) => Default::default(),
-            Err(err) => return Err(err.into()),
+        if subcommand == Subcommand::Info {
+            let format: Option<String> = args.opt_value_from_str("--format")?;
+            if let Some(format) = format.filter(|format| format != "json") {
+                bail!("Unsupported format '{format}', only 'json' is supported");
+            }
         }
-        .into_iter()
-        .collect();
+        let merge_install = args.contains("--merge-install");
+        let install_mode = if args.contains("--symlink-install") {
+            InstallMode::Symlink
+        } else {
+            InstallMode::Copy
+        };
+        let compare_contents = args.contains("--compare-contents");
+        let lint_deps = args.contains("--lint-deps");
+        let strict_package_xml = args.contains("--strict-package-xml");
+        let localhost_only = args.contains("--localhost-only");
+        let coverage = args.contains("--coverage");
+        let doc = args.contains("--doc");
+
+        // The path is canonicalized so that it can be compared to the paths reported by cargo.
+        let manifest_path = args
+            .opt_value_from_str("--manifest-path")?
+            .unwrap_or_else(|| PathBuf::from("Cargo.toml"))
+            .canonicalize()
+            .context("Package manifest does not exist")?;
+
+        let features = FeatureSelection::from_args(&forwarded_args);
+        let targets = TargetSelection::from_args(&forwarded_args);
 
         let res = Args {
+            subcommand,
             install_base,
             build_base,
             forwarded_args,
-            profile,
             arch,
             manifest_path,
             features,
+            targets,
+            merge_install,
+            install_mode,
+            compare_contents,
+            lint_deps,
+            strict_package_xml,
+            localhost_only,
+            coverage,
+            doc,
         };
 
         Ok(ArgsOrHelp::Args(res))
     }
 
+    fn parse_init(mut args: pico_args::Arguments) -> Result<InitOptions> {
+        let path = args.opt_value_from_str("--path")?;
+        let kind = if args.contains("--lib") {
+            PackageKind::Library
+        } else {
+            PackageKind::Node
+        };
+        let share_dirs = args.values_from_str("--share")?;
+        let dependencies = args.values_from_str("--dependency")?;
+        let license = args.opt_value_from_str("--license")?;
+        let name = args
+            .opt_free_from_str()?
+            .ok_or_else(|| anyhow!("The name of the package to create is missing"))?;
+        let remaining = args.finish();
+        if !remaining.is_empty() {
+            bail!("Unexpected arguments for init: {remaining:?}");
+        }
+        Ok(InitOptions {
+            name,
+            path,
+            kind,
+            share_dirs,
+            dependencies,
+            license,
+        })
+    }
+
     pub fn print_help() {
         println!("cargo-ament-build");
         println!("Wrapper around cargo-build that installs compilation results and extra files to an ament/ROS 2 install space.\n");
         println!("USAGE:");
         println!("    cargo ament-build --install-base <INSTALL_DIR> -- <CARGO-BUILD-OPTIONS>");
+        println!(
+            "    cargo ament-build uninstall --install-base <INSTALL_DIR> -- <CARGO-BUILD-OPTIONS>"
+        );
+        println!("    cargo ament-build sync-package-xml [--install-base <INSTALL_DIR>] -- <CARGO-BUILD-OPTIONS>");
+        println!("    cargo ament-build info [--format json] [--install-base <INSTALL_DIR>] -- <CARGO-BUILD-OPTIONS>");
+        println!(
+            "    cargo ament-build test [--localhost-only] [--coverage] -- <CARGO-TEST-OPTIONS>"
+        );
+        println!("    cargo ament-build lint -- <CARGO-CLIPPY-OPTIONS>");
+        println!("    cargo ament-build init <NAME> [--path <DIR>] [--lib] [--share <DIR>]... [--dependency <PACKAGE>]... [--license <SPDX-ID>]");
+        println!();
+        println!("The installed files are recorded in <TARGET_DIR>/ament_build/<PACKAGE>/install_manifest.txt,");
+        println!("and removed again by the uninstall subcommand.");
+        println!();
+        println!(
+            "When building several packages of a workspace (with --workspace, --package or from"
+        );
+        println!("a virtual manifest), each package with a package.xml is installed to");
+        println!("<INSTALL_DIR>/<PACKAGE>, or to <INSTALL_DIR> with --merge-install.");
+        println!();
+        println!("With --symlink-install, the package source, package.xml and the install_to_*");
+        println!("entries are symlinked instead of copied.");
+        println!();
+        println!(
+            "Files that are unchanged since the last install, judged by size and modification"
+        );
+        println!(
+            "time, are not installed again. With --compare-contents, files whose modification"
+        );
+        println!("time changed are also compared by their contents.");
+        println!();
+        println!("With --lint-deps, the build fails if Cargo dependencies on ROS Rust packages");
+        println!("and the dependencies in the package.xml do not match.");
+        println!();
+        println!("A package.xml whose name, version or build type does not match the Cargo.toml");
+        println!("causes warnings, or fails the build with --strict-package-xml.");
+        println!();
+        println!(
+            "With --doc, or install_docs = true in [package.metadata.ros], cargo doc --no-deps"
+        );
+        println!("is run and the documentation is installed to share/<PACKAGE>/doc/rust.");
+        println!();
+        println!("The init subcommand creates a new package with a matching Cargo.toml and");
+        println!(
+            "package.xml. It contains a node, or a library with --lib. The --share directories"
+        );
+        println!("are created and installed to share/<NAME>, and the --dependency packages are");
+        println!("added to both files.");
+        println!();
+        println!(
+            "The sync-package-xml subcommand updates the name, version, description, license,"
+        );
+        println!("maintainers and <depend> entries on ROS Rust packages in the package.xml of the");
+        println!("selected packages from their Cargo.toml, keeping everything else. A missing");
+        println!("package.xml is created.");
+        println!();
+        println!("The info subcommand prints what building and installing the selected packages");
+        println!("does as JSON: whether cargo build or check is run, and for each package its");
+        println!("binaries, libraries, install_to_* entries, ament resources and dependencies.");
+        println!();
+        println!("The test subcommand runs cargo test and writes the results of each package to");
+        println!("<TARGET_DIR>/test_results/<PACKAGE>/cargo_test.xml for colcon test-result.");
+        println!("Each test run gets a ROS_DOMAIN_ID that no parallel test run uses.");
+        println!("With --localhost-only, the tests only discover ROS nodes on the same machine.");
+        println!("With --coverage, the tests are instrumented and lcov.info and cobertura.xml");
+        println!("coverage reports are written to <TARGET_DIR>/coverage/.");
+        println!();
+        println!("The lint subcommand runs cargo clippy and cargo fmt --check and writes findings");
+        println!("as test cases to <TARGET_DIR>/test_results/<PACKAGE>/cargo_lint.xml. Whether");
+        println!("warnings fail is set with fail_on_warnings in [package.metadata.ros.lint].");
     }
 }
 
-/// Run a certain cargo verb
-pub fn cargo(args: &[OsString], verb: &str) -> Result<Option<i32>> {
+/// The outcome of a cargo invocation.
+pub struct CargoOutput {
+    /// The exit code of cargo. None if it was terminated by a signal.
+    pub exit_code: Option<i32>,
+    /// The artifacts that cargo reported in its `compiler-artifact` messages.
+    pub artifacts: Vec<Artifact>,
+}
+
+/// Run a certain cargo verb, with additional environment variables
+///
+/// Cargo is asked for JSON messages on stdout so that the produced artifacts are known exactly,
+/// while diagnostics are still rendered in human-readable form on stderr.
+pub fn cargo(args: &[OsString], verb: &str, envs: &[(&str, String)]) -> Result<CargoOutput> {
     let mut cmd = Command::new("cargo");
     // "check" and "build" have compatible arguments
     cmd.arg(verb);
+    cmd.arg("--message-format=json-render-diagnostics");
     for arg in args {
         cmd.arg(arg);
     }
-    let exit_status = cmd
-        .status()
-        .context("Failed to spawn 'cargo build' subprocess")?;
-    Ok(exit_status.code())
+    cmd.envs(envs.iter().map(|(key, value)| (key, value)));
+    cmd.stdout(Stdio::piped());
+    let mut child = cmd
+        .spawn()
+        .with_context(|| format!("Failed to spawn 'cargo {verb}' subprocess"))?;
+    // unwrap is ok since stdout is piped
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let artifacts = collect_artifacts(stdout)?;
+    let exit_status = child
+        .wait()
+        .with_context(|| format!("Failed to wait for 'cargo {verb}' subprocess"))?;
+    Ok(CargoOutput {
+        exit_code: exit_status.code(),
+        artifacts,
+    })
+}
+
+/// Collects the `compiler-artifact` messages from cargo's JSON output.
+///
+/// Lines that are not JSON messages (e.g. printed by build scripts) are passed through to stdout.
+fn collect_artifacts(reader: impl BufRead) -> Result<Vec<Artifact>> {
+    let mut artifacts = Vec::new();
+    for message in Message::parse_stream(reader) {
+        match message.context("Failed to read the output of cargo")? {
+            Message::CompilerArtifact(artifact) => artifacts.push(artifact),
+            Message::TextLine(line) => println!("{line}"),
+            _ => {}
+        }
+    }
+    Ok(artifacts)
+}
+
+/// Read the workspace containing a package as resolved by `cargo metadata --no-deps`
+///
+/// In contrast to parsing the manifest directly, this takes workspace inheritance (e.g.
+/// `version.workspace = true`) and automatically discovered targets into account.
+pub fn read_metadata(manifest_path: impl AsRef<Path>) -> Result<Metadata> {
+    let manifest_path = manifest_path.as_ref();
+    MetadataCommand::new()
+        .manifest_path(manifest_path)
+        .no_deps()
+        .exec()
+        .with_context(|| {
+            format!(
+                "Failed to run 'cargo metadata' for '{}'",
+                manifest_path.display()
+            )
+        })
+}
+
+/// Find the package with the given manifest among the packages of a workspace
+pub fn find_package(metadata: &Metadata, manifest_path: impl AsRef<Path>) -> Result<&Package> {
+    let manifest_path = manifest_path.as_ref();
+    metadata
+        .packages
+        .iter()
+        .find(|package| package.manifest_path == manifest_path)
+        .ok_or_else(|| {
+            anyhow!(
+                "'cargo metadata' did not list the package at '{}'",
+                manifest_path.display()
+            )
+        })
+}
+
+/// Select the packages of a workspace that cargo builds
+///
+/// Without `--workspace` or `--package`, this is the package at `manifest_path`, or the default
+/// members of the workspace if it is a virtual manifest. When building workspace members, only
+/// those with a `package.xml` are ROS packages and therefore selected.
+pub fn select_packages<'a>(
+    metadata: &'a Metadata,
+    manifest_path: impl AsRef<Path>,
+    targets: &TargetSelection,
+) -> Result<Vec<&'a Package>> {
+    let manifest_path = manifest_path.as_ref();
+    let members = select_workspace_members(metadata, manifest_path, targets);
+    if !targets.selects_workspace_members() && find_package(metadata, manifest_path).is_ok() {
+        return Ok(members);
+    }
+    let ros_packages: Vec<_> = members
+        .into_iter()
+        .filter(|package| {
+            package
+                .manifest_path
+                .with_file_name("package.xml")
+                .is_file()
+        })
+        .collect();
+    if ros_packages.is_empty() {
+        bail!(
+            "None of the selected packages in the workspace at '{}' contains a package.xml",
+            metadata.workspace_root
+        );
+    }
+    Ok(ros_packages)
+}
+
+/// Select the members of a workspace that cargo builds, like [`select_packages`], but whether
+/// they have a `package.xml` or not.
+pub fn select_workspace_members<'a>(
+    metadata: &'a Metadata,
+    manifest_path: impl AsRef<Path>,
+    targets: &TargetSelection,
+) -> Vec<&'a Package> {
+    let manifest_path = manifest_path.as_ref();
+    let members: Vec<_> = if targets.selects_workspace_members() {
+        metadata
+            .packages
+            .iter()
+            .filter(|package| metadata.workspace_members.contains(&package.id))
+            .filter(|package| targets.selects_package(&package.name))
+            .collect()
+    } else if let Ok(package) = find_package(metadata, manifest_path) {
+        return vec![package];
+    } else if metadata.workspace_default_members.is_available() {
+        metadata
+            .packages
+            .iter()
+            .filter(|package| metadata.workspace_default_members.contains(&package.id))
+            .collect()
+    } else {
+        metadata
+            .packages
+            .iter()
+            .filter(|package| metadata.workspace_members.contains(&package.id))
+            .collect()
+    };
+    sort_by_dependencies(&members)
+}
+
+/// Orders packages so that each one comes after the packages it has path dependencies on.
+///
+/// This is the order to install them in, since the installed manifest of a package refers to
+/// the installed source of its path dependencies.
+fn sort_by_dependencies<'a>(packages: &[&'a Package]) -> Vec<&'a Package> {
+    fn visit<'a>(
+        package: &'a Package,
+        packages: &[&'a Package],
+        visited: &mut HashSet<&'a str>,
+        sorted: &mut Vec<&'a Package>,
+    ) {
+        // Cycles through dev-dependencies are broken at the package that was visited first
+        if !visited.insert(&package.name) {
+            return;
+        }
+        for dependency in &package.dependencies {
+            let dependency = packages
+                .iter()
+                .find(|package| dependency.path.is_some() && package.name == dependency.name);
+            if let Some(dependency) = dependency {
+                visit(dependency, packages, visited, sorted);
+            }
+        }
+        sorted.push(package);
+    }
+
+    let mut visited = HashSet::new();
+    let mut sorted = Vec::new();
+    for package in packages {
+        visit(package, packages, &mut visited, &mut sorted);
+    }
+    sorted
+}
+
+/// Whether the package contains neither binaries nor libraries that are usable outside of Rust.
+///
+/// Such packages only need to be checked, not built.
+pub fn is_pure_library(package: &Package) -> bool {
+    package.targets.iter().all(|target| {
+        target.kind.iter().all(|kind| {
+            matches!(
+                kind,
+                TargetKind::Lib
+                    | TargetKind::RLib
+                    | TargetKind::ProcMacro
+                    | TargetKind::Example
+                    | TargetKind::Test
+                    | TargetKind::Bench
+                    | TargetKind::CustomBuild
+            )
+        })
+    })
+}
+
+/// The cargo command that is run for the packages: `check` if they are all pure libraries,
+/// `build` otherwise.
+pub fn cargo_verb(packages: &[&Package]) -> &'static str {
+    if packages.iter().all(|package| is_pure_library(package)) {
+        "check"
+    } else {
+        "build"
+    }
 }
 
 /// Create an ament resource index marker file for a package
 ///
-/// This function registers a package to ament by creating an empty marker file at
-/// `share/ament_index/resource_index` with the package name as filename.  
+/// This function registers a package to ament by creating a marker file at
+/// `share/ament_index/resource_index/<marker_dir>` with the package name as filename. The file is
+/// empty unless content is given, e.g. the list of interface files for `rosidl_interfaces`.
+///
+/// The file is written atomically. If a marker with different content exists that was not part
+/// of the previous install of this package, it belongs to another package with the same name,
+/// which is an error.
 ///
 /// The presence of this file is used by ament and colcon to discover installed packages and other resources.
 /// For more information:
@@ -138,6 +567,8 @@ pub fn create_package_marker(
     install_base: impl AsRef<Path>,
     marker_dir: &str,
     package_name: &str,
+    content: Option<&str>,
+    install_manifest: &mut InstallManifest,
 ) -> Result<()> {
     let mut path = install_base
         .as_ref()
@@ -153,50 +584,324 @@ pub fn create_package_marker(
             )
         })?;
     path.push(package_name);
-    File::create(&path)
+    let content = content.unwrap_or_default();
+    let installed = install_manifest.installed_path(&path);
+    if let Ok(existing) = std::fs::read_to_string(&installed) {
+        if existing != content && !install_manifest.was_installed_before(&path) {
+            bail!(
+                "The package marker '{}' already exists with different content, so another package \
+                 in the install space registers the '{marker_dir}' resource under the same name",
+                installed.display()
+            );
+        }
+    }
+    install_contents(content, &path, install_manifest)
         .with_context(|| format!("Failed to create package marker '{}'", path.display()))?;
     Ok(())
 }
 
+/// Finds the names of the packages that registered a resource type in any of the prefixes.
+pub fn find_resources(prefixes: &[PathBuf], resource_type: &str) -> Result<HashSet<String>> {
+    let mut names = HashSet::new();
+    for prefix in prefixes {
+        let dir = prefix
+            .join("share/ament_index/resource_index")
+            .join(resource_type);
+        if !dir.is_dir() {
+            continue;
+        }
+        for entry in std::fs::read_dir(&dir)
+            .with_context(|| format!("Failed to read '{}'", dir.display()))?
+        {
+            let file_name = entry?.file_name();
+            // Hidden files are not resources, e.g. temporary files of an ongoing install
+            if let Some(name) = file_name.to_str().filter(|name| !name.starts_with('.')) {
+                names.insert(name.to_owned());
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// Registers the `package_run_dependencies` and `parent_prefix_path` resources of a package, like
+/// ament_cmake does.
+///
+/// The run dependencies are taken from the `package.xml` and separated by `;`. The parent prefix
+/// path lists the underlays the package was built against, in the format of `AMENT_PREFIX_PATH`.
+pub fn register_dependency_resources(
+    install_base: impl AsRef<Path>,
+    package_name: &str,
+    package_xml: &PackageXml,
+    parent_prefixes: &[PathBuf],
+    install_manifest: &mut InstallManifest,
+) -> Result<()> {
+    let run_dependencies = package_xml.run_dependencies().join(";");
+    create_package_marker(
+        &install_base,
+        "package_run_dependencies",
+        package_name,
+        Some(&run_dependencies),
+        install_manifest,
+    )?;
+    let parent_prefix_path = std::env::join_paths(parent_prefixes)
+        .context("The parent prefix path contains an invalid path")?;
+    create_package_marker(
+        &install_base,
+        "parent_prefix_path",
+        package_name,
+        Some(&parent_prefix_path.to_string_lossy()),
+        install_manifest,
+    )
+}
+
+/// How files from the source tree are placed into the install space.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InstallMode {
+    /// Copy files, so that the install space is independent of the source tree
+    #[default]
+    Copy,
+    /// Create symlinks back to the source tree, like `colcon build --symlink-install`, so that
+    /// changes to e.g. launch files take effect without rebuilding
+    Symlink,
+}
+
+/// Installs a file or directory into `dest_dir` according to the install mode.
+fn install_path(
+    src: impl AsRef<Path>,
+    dest_dir: impl AsRef<Path>,
+    mode: InstallMode,
+    install_manifest: &mut InstallManifest,
+) -> Result<()> {
+    let src = src.as_ref();
+    let dest = dest_dir.as_ref().join(src.file_name().unwrap());
+    // Copying through a symlink from an earlier symlink install would overwrite the source
+    // itself, and a symlink cannot be created over an existing file.
+    if let Ok(metadata) = dest.symlink_metadata() {
+        if metadata.is_symlink() || mode == InstallMode::Symlink {
+            remove_path(&dest)?;
+        }
+    }
+    match mode {
+        InstallMode::Copy => copy(src, dest_dir, install_manifest),
+        InstallMode::Symlink => {
+            let installed = install_manifest.installed_path(&dest);
+            if src.canonicalize().ok() == std::fs::read_link(&installed).ok()
+                && !has_symlink_ancestor(&installed)
+            {
+                install_manifest.record_unchanged(&dest);
+            } else {
+                symlink(src, &dest)?;
+                install_manifest.record(&dest);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Whether one of the directories containing the path is a symlink, e.g. from an earlier
+/// symlink install, in which case the path is in the source tree rather than the install base.
+///
+/// This expects a path below a canonical install base, any other path is treated as if it
+/// contained a symlink.
+fn has_symlink_ancestor(path: &Path) -> bool {
+    match path.parent().map(|parent| (parent, parent.canonicalize())) {
+        Some((parent, Ok(canonical))) => parent != canonical,
+        _ => true,
+    }
+}
+
+/// Removes a file, symlink or directory.
+fn remove_path(path: &Path) -> Result<()> {
+    let metadata = path.symlink_metadata()?;
+    if metadata.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    }
+    .with_context(|| format!("Failed to remove '{}'", path.display()))
+}
+
+/// Creates a symlink at `dest` pointing to the absolute path of `src`.
+fn symlink(src: &Path, dest: &Path) -> Result<()> {
+    let src = src
+        .canonicalize()
+        .with_context(|| format!("File or dir '{}' does not exist", src.display()))?;
+    #[cfg(unix)]
+    let result = std::os::unix::fs::symlink(&src, dest);
+    #[cfg(windows)]
+    let result = if src.is_dir() {
+        std::os::windows::fs::symlink_dir(&src, dest)
+    } else {
+        std::os::windows::fs::symlink_file(&src, dest)
+    };
+    result.with_context(|| {
+        format!(
+            "Failed to create symlink '{}' to '{}'.",
+            dest.display(),
+            src.display()
+        )
+    })
+}
+
 /// Copies files or directories recursively.
-fn copy(src: impl AsRef<Path>, dest_dir: impl AsRef<Path>) -> Result<()> {
+fn copy(
+    src: impl AsRef<Path>,
+    dest_dir: impl AsRef<Path>,
+    install_manifest: &mut InstallManifest,
+) -> Result<()> {
     let src = src.as_ref();
     let dest = dest_dir.as_ref().join(src.file_name().unwrap());
     if src.is_dir() {
         std::fs::create_dir_all(&dest)?;
+        let mut is_empty = true;
         for entry in std::fs::read_dir(src)? {
             let entry = entry?;
+            is_empty = false;
             if entry.file_type()?.is_dir() {
-                copy(entry.path(), &dest)?;
+                copy(entry.path(), &dest, install_manifest)?;
+            } else {
+                install_file(
+                    &entry.path(),
+                    &dest.join(entry.file_name()),
+                    install_manifest,
+                )?;
+            }
+        }
+        // Only recorded paths end up in the install base, so empty directories are recorded too
+        if is_empty {
+            let installed = install_manifest.installed_path(&dest);
+            let is_installed = installed
+                .symlink_metadata()
+                .is_ok_and(|metadata| metadata.is_dir())
+                && !has_symlink_ancestor(&installed);
+            if is_installed {
+                install_manifest.record_unchanged(&dest);
             } else {
-                std::fs::copy(entry.path(), dest.join(entry.file_name()))?;
+                install_manifest.record(&dest);
             }
         }
     } else if src.is_file() {
-        std::fs::copy(&src, &dest).with_context(|| {
+        install_file(src, &dest, install_manifest)?;
+    } else {
+        bail!("File or dir '{}' does not exist", src.display())
+    }
+    Ok(())
+}
+
+/// Copies a single file, unless the file in the install base is already up to date.
+///
+/// The modification time of the source is carried over, so that the next install can tell
+/// whether the file changed.
+fn install_file(src: &Path, dest: &Path, install_manifest: &mut InstallManifest) -> Result<()> {
+    let installed = install_manifest.installed_path(dest);
+    if is_up_to_date(src, &installed, install_manifest.compares_contents())? {
+        install_manifest.record_unchanged(dest);
+        return Ok(());
+    }
+    std::fs::copy(src, dest).with_context(|| {
+        format!(
+            "Failed to copy '{}' to '{}'.",
+            src.display(),
+            dest.display()
+        )
+    })?;
+    let modified = std::fs::metadata(src)?.modified()?;
+    File::options()
+        .write(true)
+        .open(dest)?
+        .set_modified(modified)
+        .with_context(|| {
             format!(
-                "Failed to copy '{}' to '{}'.",
-                src.display(),
+                "Failed to set the modification time of '{}'",
                 dest.display()
             )
         })?;
-    } else {
-        bail!("File or dir '{}' does not exist", src.display())
+    install_manifest.record(dest);
+    Ok(())
+}
+
+/// Writes generated contents to a file, unless the file in the install base already has them.
+///
+/// The contents are written to a temporary file first, so that the file is replaced atomically.
+fn install_contents(
+    contents: &str,
+    dest: &Path,
+    install_manifest: &mut InstallManifest,
+) -> Result<()> {
+    let installed = install_manifest.installed_path(dest);
+    if !installed.is_symlink()
+        && !has_symlink_ancestor(&installed)
+        && std::fs::read(&installed).is_ok_and(|installed| installed == contents.as_bytes())
+    {
+        install_manifest.record_unchanged(dest);
+        return Ok(());
     }
+    // unwrap is ok since the destination is a file in a directory
+    let tmp = dest.with_file_name(format!(
+        ".{}.tmp",
+        dest.file_name().unwrap().to_string_lossy()
+    ));
+    std::fs::write(&tmp, contents)
+        .and_then(|()| std::fs::rename(&tmp, dest))
+        .with_context(|| format!("Failed to write '{}'", dest.display()))?;
+    install_manifest.record(dest);
     Ok(())
 }
 
+/// Whether an installed file is a copy of the source file.
+///
+/// Files are compared by size and modification time, and optionally by their contents if only
+/// the modification time differs, e.g. after switching git branches back and forth.
+fn is_up_to_date(src: &Path, installed: &Path, compare_contents: bool) -> Result<bool> {
+    if installed.is_symlink() || !installed.is_file() || has_symlink_ancestor(installed) {
+        return Ok(false);
+    }
+    let src_metadata = std::fs::metadata(src)?;
+    let installed_metadata = std::fs::metadata(installed)?;
+    if src_metadata.len() != installed_metadata.len() {
+        return Ok(false);
+    }
+    if src_metadata.modified()? == installed_metadata.modified()? {
+        return Ok(true);
+    }
+    if !compare_contents {
+        return Ok(false);
+    }
+    let mut src = BufReader::new(File::open(src)?);
+    let mut installed = BufReader::new(File::open(installed)?);
+    loop {
+        let src_buf = src.fill_buf()?;
+        let installed_buf = installed.fill_buf()?;
+        let len = src_buf.len().min(installed_buf.len());
+        if len == 0 {
+            return Ok(src_buf.len() == installed_buf.len());
+        }
+        if src_buf[..len] != installed_buf[..len] {
+            return Ok(false);
+        }
+        src.consume(len);
+        installed.consume(len);
+    }
+}
+
 /// Copy the source code of the package to the install space
 ///
-/// Specifically, `${install_base}/share/${package}/rust`.
+/// Specifically, `${install_base}/share/${package}/rust`. The manifest is normalized so that it
+/// builds without the workspace the package may be part of, so it is always written as a file
+/// even when symlinking. Path dependencies are looked up in the given prefixes, in order.
 pub fn install_package(
     install_base: impl AsRef<Path>,
-    package_path: impl AsRef<Path>,
-    manifest_path: impl AsRef<Path>,
-    package_name: &str,
-    manifest: &Manifest,
+    package: &Package,
+    workspace_root: impl AsRef<Path>,
+    prefixes: &[PathBuf],
+    mode: InstallMode,
+    install_manifest: &mut InstallManifest,
 ) -> Result<()> {
-    let manifest_path = manifest_path.as_ref();
+    let package_name = &package.name;
+    let manifest_path = package.manifest_path.as_std_path();
+    let package_path = manifest_path
+        .parent()
+        .ok_or(anyhow!("Manifest path must have a parent."))?;
 
     // Install source code
     // This is special-cased (and not simply added to the list of things to install below)
@@ -204,32 +909,34 @@ pub fn install_package(
     dest_dir.push("share");
     dest_dir.push(package_name);
     dest_dir.push("rust");
-    if dest_dir.is_dir() {
-        std::fs::remove_dir_all(&dest_dir)?;
-    }
     DirBuilder::new().recursive(true).create(&dest_dir)?;
-    // unwrap is ok since it has been validated in main
-    let package = manifest.package.as_ref().unwrap();
-    // The entry for the build script can be empty (in which case build.rs is implicitly used if it
-    // exists), or a path, or false (in which case build.rs is not implicitly used).
-    let build = match &package.build {
-        Some(StringOrBool::Bool(false)) => None,
-        Some(StringOrBool::String(path)) => Some(path.as_str()),
-        Some(_) => bail!("Value of 'build' is not a string or boolean"),
-        None => None,
-    };
-    if let Some(filename) = build {
-        let src = package_path.as_ref().join(filename);
-        copy(src, &dest_dir)?;
+    // Cargo lists the build script as a target if there is one, whether it is the implicit
+    // build.rs or set with the 'build' key.
+    let build_scripts = package
+        .targets
+        .iter()
+        .filter(|target| target.is_custom_build());
+    for build_script in build_scripts {
+        install_path(&build_script.src_path, &dest_dir, mode, install_manifest)?;
     }
 
-    copy(package_path.as_ref().join("src"), &dest_dir)?;
-    copy(manifest_path, &dest_dir)?;
+    install_path(package_path.join("src"), &dest_dir, mode, install_manifest)?;
+
+    let normalized_manifest =
+        manifest::normalize_manifest(manifest_path, workspace_root.as_ref(), prefixes)?;
+    install_contents(
+        &normalized_manifest,
+        &dest_dir.join("Cargo.toml"),
+        install_manifest,
+    )
+    .context("Failed to write the normalized manifest")?;
 
     // unwrap is ok since we pushed to the path before
-    copy(
-        package_path.as_ref().join("package.xml"),
+    install_path(
+        package_path.join("package.xml"),
         dest_dir.parent().unwrap(),
+        mode,
+        install_manifest,
     )?;
 
     // The lockfile may not exist in the case that the package is in a
@@ -237,107 +944,294 @@ pub fn install_package(
     // virtual Cargo.toml.
     let lockfile_path = manifest_path.with_extension("lock");
     if lockfile_path.is_file() {
-        copy(&lockfile_path, &dest_dir)?;
+        install_path(&lockfile_path, &dest_dir, mode, install_manifest)?;
     }
 
     Ok(())
 }
 
+/// Select the binaries that cargo builds with the given active features and target selection
+///
+/// Binaries are skipped by cargo if one of their `required-features` is missing.
+pub fn expected_binaries<'a>(
+    package: &'a Package,
+    features: &HashSet<String>,
+    targets: &TargetSelection,
+) -> Vec<&'a Target> {
+    package
+        .targets
+        .iter()
+        .filter(|target| target.is_bin() && targets.selects_bin(&package.name, &target.name))
+        .filter(|target| {
+            target
+                .required_features
+                .iter()
+                .all(|feature| features.contains(feature))
+        })
+        .collect()
+}
+
 /// Copy the binaries to a location where they will be found by ROS 2 tools (the lib dir)
+///
+/// The executables of the given binaries and the `cdylib`/`staticlib` files are taken from the
+/// artifacts reported by cargo, wherever the target directory is.
 pub fn install_binaries(
     install_base: impl AsRef<Path>,
-    build_base: impl AsRef<Path>,
     package_name: &str,
-    profile: &str,
-    arch: Option<&str>,
-    features: &HashSet<String>,
-    binaries: &[Product],
+    binaries: &[&Target],
+    artifacts: &[Artifact],
+    install_manifest: &mut InstallManifest,
 ) -> Result<()> {
-    let src_dir = if let Some(arch) = arch {
-        build_base.as_ref().join(arch).join(profile)
-    } else {
-        build_base.as_ref().join(profile)
-    };
-
     let dest_dir = install_base.as_ref().join("lib").join(package_name);
-    if dest_dir.is_dir() {
-        std::fs::remove_dir_all(&dest_dir)?;
-    }
-    // Copy binaries
+    let mut files = Vec::new();
     for binary in binaries {
-        let missing_feature = binary
-            .required_features
+        let executable = artifacts
             .iter()
-            .any(|feature| !features.contains(feature));
-        if missing_feature {
-            // The build directory will not contain this binary because one of
-            // its required features is missing. We should skip our attempt to
-            // install it.
-            continue;
-        }
-
-        let name = binary
-            .name
-            .as_ref()
-            .ok_or(anyhow!("Binary without name found"))?;
-        let src = src_dir.join(name);
-        let dest = dest_dir.join(name);
-        #[cfg(target_os = "windows")]
-        let dest = dest.with_extension("exe");
-        #[cfg(target_os = "windows")]
-        let src = src.with_extension("exe");
+            // The unit tests of a binary are built from the same target
+            .filter(|artifact| {
+                artifact.target.is_bin()
+                    && artifact.target.name == binary.name
+                    && !artifact.profile.test
+            })
+            .find_map(|artifact| artifact.executable.as_deref())
+            .ok_or_else(|| anyhow!("Binary '{}' was not built by cargo", binary.name))?;
+        files.push(executable);
+    }
+    files.extend(artifacts.iter().flat_map(library_files));
+    for src in files {
+        // unwrap is ok since cargo only reports paths to files
+        let dest = dest_dir.join(src.file_name().unwrap());
         // Create destination directory
         DirBuilder::new().recursive(true).create(&dest_dir)?;
-        std::fs::copy(&src, &dest)
-            .context(format!("Failed to copy binary from '{}'", src.display()))?;
-    }
-    // If there is a shared or static library, copy it too
-    // See https://doc.rust-lang.org/reference/linkage.html for an explanation of suffixes
-    let prefix_suffix_combinations = [
-        ("lib", "so"),
-        ("lib", "dylib"),
-        ("lib", "a"),
-        ("", "dll"),
-        ("", "lib"),
-    ];
-    for (prefix, suffix) in prefix_suffix_combinations {
-        let filename = String::from(prefix) + package_name + "." + suffix;
-        let src = src_dir.join(&filename);
-        let dest = dest_dir.join(filename);
-        if src.is_file() {
-            // Create destination directory
-            DirBuilder::new().recursive(true).create(&dest_dir)?;
-            std::fs::copy(&src, &dest)
-                .context(format!("Failed to copy library from '{}'", src.display()))?;
+        install_file(src.as_std_path(), &dest, install_manifest)?;
+    }
+    Ok(())
+}
+
+/// Selects the shared and static libraries among the files of an artifact.
+///
+/// Only library targets are considered, examples built as libraries have the kind `example`.
+fn library_files(artifact: &Artifact) -> Vec<&Utf8Path> {
+    if artifact.target.is_cdylib() || artifact.target.is_staticlib() {
+        // The filenames also contain other crate types of the same target and auxiliary files
+        // like debug info, so only shared and static libraries are picked.
+        // See https://doc.rust-lang.org/reference/linkage.html for an explanation of suffixes
+        artifact
+            .filenames
+            .iter()
+            .filter(|path| matches!(path.extension(), Some("so" | "dylib" | "a" | "dll" | "lib")))
+            .map(|path| path.as_path())
+            .collect()
+    } else {
+        Vec::new()
+    }
+}
+
+/// Whether the file is a shared library, which needs to be on the library path to be loaded.
+pub fn is_shared_library(path: impl AsRef<Path>) -> bool {
+    path.as_ref()
+        .extension()
+        .is_some_and(|extension| extension == "so" || extension == "dylib" || extension == "dll")
+}
+
+/// Install the documentation of a package to `share/<package>/doc/rust` and register it as a
+/// `rust_docs` resource
+///
+/// The crates are found through the `index.html` files in the artifacts that `cargo doc`
+/// reported for the package. The doc directory may contain other crates of the workspace as well,
+/// so only the crates of this package, their rendered sources and the `static.files` that the
+/// pages use are installed. The search index and crate list in the doc directory cover all
+/// documented crates, so they are left out. The marker lists the index pages of the crates
+/// relative to the install base, one per line.
+pub fn install_docs(
+    install_base: impl AsRef<Path>,
+    package_name: &str,
+    artifacts: &[Artifact],
+    install_manifest: &mut InstallManifest,
+) -> Result<()> {
+    let index_pages: Vec<_> = artifacts
+        .iter()
+        .flat_map(|artifact| &artifact.filenames)
+        .filter(|path| path.file_name() == Some("index.html"))
+        .collect();
+    let Some(doc_dir) = index_pages.first().and_then(|path| path.parent()?.parent()) else {
+        bail!("cargo doc did not document package '{package_name}'");
+    };
+    let crate_dirs: Vec<_> = index_pages
+        .iter()
+        .filter_map(|path| path.parent()?.file_name())
+        .collect();
+    let doc_path = Path::new("share")
+        .join(package_name)
+        .join("doc")
+        .join("rust");
+    let dest_dir = install_base.as_ref().join(&doc_path);
+    DirBuilder::new().recursive(true).create(&dest_dir)?;
+    for entry in std::fs::read_dir(doc_dir)
+        .with_context(|| format!("Failed to read the documentation in '{doc_dir}'"))?
+    {
+        let path = entry?.path();
+        // unwrap is ok since the entries of a directory have a file name
+        let name = path.file_name().unwrap().to_string_lossy();
+        if name == "src" {
+            // The rendered sources are grouped by crate as well
+            for crate_dir in &crate_dirs {
+                let src = path.join(crate_dir);
+                if src.is_dir() {
+                    let dest = dest_dir.join("src");
+                    DirBuilder::new().recursive(true).create(&dest)?;
+                    install_path(&src, &dest, InstallMode::Copy, install_manifest)?;
+                }
+            }
+        } else if crate_dirs.contains(&name.as_ref()) || name == "static.files" {
+            install_path(&path, &dest_dir, InstallMode::Copy, install_manifest)?;
         }
     }
+    let index_pages: Vec<_> = crate_dirs
+        .iter()
+        .map(|crate_dir| {
+            doc_path
+                .join(crate_dir)
+                .join("index.html")
+                .to_string_lossy()
+                .into_owned()
+        })
+        .collect();
+    create_package_marker(
+        &install_base,
+        "rust_docs",
+        package_name,
+        Some(&index_pages.join("\n")),
+        install_manifest,
+    )
+}
+
+/// Register the resources of a package in the ament index
+///
+/// Each entry maps a resource type to the content of the marker file, given directly as a
+/// string or as `{ file = "path" }` relative to the package:
+/// ```toml
+/// [package.metadata.ros.resources]
+/// my_resource_type = "some content"
+/// rviz_common__pluginlib__plugin = { file = "plugin_description.xml" }
+/// ```
+fn register_resources(
+    install_base: impl AsRef<Path>,
+    package_path: impl AsRef<Path>,
+    package_name: &str,
+    resources: &serde_json::Map<String, Value>,
+    install_manifest: &mut InstallManifest,
+) -> Result<()> {
+    for (resource_type, value) in resources {
+        let content =
+            resource_content(package_path.as_ref(), resource_type, value).with_context(|| {
+                format!("Invalid [package.metadata.ros.resources] entry '{resource_type}'")
+            })?;
+        create_package_marker(
+            &install_base,
+            resource_type,
+            package_name,
+            Some(&content),
+            install_manifest,
+        )?;
+    }
     Ok(())
 }
 
+/// Reads the marker content of a resource declared in the package metadata.
+fn resource_content(package_path: &Path, resource_type: &str, value: &Value) -> Result<String> {
+    let is_valid_type = Path::new(resource_type)
+        .components()
+        .map(|component| matches!(component, std::path::Component::Normal(_)))
+        .eq([true]);
+    if !is_valid_type {
+        bail!("'{resource_type}' is not a valid resource type");
+    }
+    // These are registered automatically, so declaring them would be a mistake
+    if [
+        "packages",
+        "rust_packages",
+        "package_run_dependencies",
+        "parent_prefix_path",
+        "rust_docs",
+    ]
+    .contains(&resource_type)
+    {
+        bail!("The '{resource_type}' resource is registered automatically");
+    }
+    match value {
+        Value::String(content) => Ok(content.clone()),
+        Value::Object(table) => match table.get("file") {
+            Some(Value::String(file)) if table.len() == 1 => {
+                let path = package_path.join(file);
+                std::fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read '{}'", path.display()))
+            }
+            _ => bail!("The table must contain only the 'file' key"),
+        },
+        _ => bail!("The value must be a string or a table"),
+    }
+}
+
 /// Copy selected files/directories to the share dir.
+///
+/// The variables in `[package.metadata.ros.environment]` are turned into an environment hook,
+/// and the resources in `[package.metadata.ros.resources]` are registered in the ament index.
 pub fn install_files_from_metadata(
     install_base: impl AsRef<Path>,
     package_path: impl AsRef<Path>,
     package_name: &str,
-    metadata: Option<&Value>,
+    metadata: &Value,
+    mode: InstallMode,
+    install_manifest: &mut InstallManifest,
 ) -> Result<()> {
     // Unpack the metadata entry
-    let metadata_table = match metadata {
-        Some(Value::Table(tab)) => tab,
+    let metadata_ros_table = match metadata.get("ros") {
+        Some(Value::Object(tab)) => tab,
         _ => return Ok(()),
     };
-    let metadata_ros_table = match metadata_table.get("ros") {
-        Some(Value::Table(tab)) => tab,
-        _ => return Ok(()),
-    };
-    for subdir in ["share", "include", "lib"] {
+    if let Some(hook) = metadata_environment_hook(metadata)? {
+        install_environment_hook(&install_base, package_name, &hook, install_manifest)?;
+    }
+    match metadata_ros_table.get("resources") {
+        Some(Value::Object(resources)) => register_resources(
+            &install_base,
+            &package_path,
+            package_name,
+            resources,
+            install_manifest,
+        )?,
+        Some(_) => bail!("The [package.metadata.ros.resources] entry is not a table"),
+        None => {}
+    }
+    for (subdir, install_entries) in metadata_install_entries(metadata)? {
         let dest = install_base.as_ref().join(subdir).join(package_name);
         DirBuilder::new().recursive(true).create(&dest)?;
+        for rel_path in install_entries {
+            let src = package_path.as_ref().join(&rel_path);
+            install_path(&src, &dest, mode, install_manifest).with_context(|| {
+                format!("Could not process [package.metadata.ros.install_to_{subdir}] entry '{rel_path}'",)
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// The `install_to_share`, `install_to_include` and `install_to_lib` entries in the package
+/// metadata, along with the directory they are installed to.
+///
+/// The entries are returned in this order, and missing ones are left out.
+pub fn metadata_install_entries(metadata: &Value) -> Result<Vec<(&'static str, Vec<String>)>> {
+    let Some(Value::Object(metadata_ros_table)) = metadata.get("ros") else {
+        return Ok(Vec::new());
+    };
+    let mut entries = Vec::new();
+    for subdir in ["share", "include", "lib"] {
         let key = format!("install_to_{subdir}");
         let install_array = match metadata_ros_table.get(&key) {
             Some(Value::Array(arr)) => arr,
             Some(_) => bail!("The [package.metadata.ros.{key}] entry is not an array"),
-            _ => return Ok(()),
+            _ => continue,
         };
         let install_entries = install_array
             .iter()
@@ -348,36 +1242,326 @@ pub fn install_files_from_metadata(
                 }
             })
             .collect::<Result<Vec<_>, _>>()?;
-        for rel_path in install_entries {
-            let src = package_path.as_ref().join(&rel_path);
-            copy(&src, &dest).with_context(|| {
-                format!("Could not process [package.metadata.ros.{key}] entry '{rel_path}'",)
-            })?;
-        }
+        entries.push((subdir, install_entries));
     }
-    Ok(())
+    Ok(entries)
+}
+
+/// Whether the documentation of the package is installed even without `--doc`, because it sets
+/// `install_docs = true` in `[package.metadata.ros]`.
+pub fn installs_docs(package: &Package) -> bool {
+    package
+        .metadata
+        .get("ros")
+        .and_then(|ros| ros.get("install_docs"))
+        .and_then(|install_docs| install_docs.as_bool())
+        .unwrap_or(false)
+}
+
+/// Whether the package is included in ros-env, which means that `colcon-ros-cargo` must not
+/// patch it, so it is not registered as a `rust_packages` resource.
+pub fn includes_ros_env(package: &Package) -> bool {
+    package
+        .metadata
+        .get("ros-env")
+        .and_then(|ros_env| ros_env.get("include"))
+        .and_then(|include| include.as_bool())
+        .unwrap_or(false)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::HashMap;
+    use crate::test_util::{artifact, bin_target, package};
     use std::fs::File;
     use std::io::Write;
     use tempfile::tempdir;
 
+    #[test]
+    fn test_find_package_in_workspace() -> Result<()> {
+        let tmp = tempdir()?;
+        let workspace = tmp.path();
+        std::fs::write(
+            workspace.join("Cargo.toml"),
+            "[workspace]\nmembers = [\"lib_pkg\", \"node_pkg\"]\n\n[workspace.package]\nversion = \"1.2.3\"\n",
+        )?;
+        for (member, main) in [("lib_pkg", "lib.rs"), ("node_pkg", "main.rs")] {
+            std::fs::create_dir_all(workspace.join(member).join("src"))?;
+            std::fs::write(
+                workspace.join(member).join("Cargo.toml"),
+                format!("[package]\nname = \"{member}\"\nversion.workspace = true\nedition = \"2021\"\n\n[package.metadata.ros]\ninstall_to_share = [\"launch\"]\n"),
+            )?;
+            File::create(workspace.join(member).join("src").join(main))?;
+        }
+
+        let lib_manifest = workspace.join("lib_pkg/Cargo.toml").canonicalize()?;
+        let node_manifest = workspace.join("node_pkg/Cargo.toml").canonicalize()?;
+        let metadata = read_metadata(&node_manifest)?;
+        let lib_pkg = find_package(&metadata, &lib_manifest)?;
+        let node_pkg = find_package(&metadata, &node_manifest)?;
+
+        assert_eq!(node_pkg.name, "node_pkg");
+        assert_eq!(node_pkg.version.to_string(), "1.2.3");
+        assert_eq!(
+            node_pkg.metadata["ros"]["install_to_share"],
+            serde_json::json!(["launch"])
+        );
+        assert!(is_pure_library(lib_pkg));
+        assert!(!is_pure_library(node_pkg));
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_packages() -> Result<()> {
+        let tmp = tempdir()?;
+        let workspace = tmp.path();
+        std::fs::write(
+            workspace.join("Cargo.toml"),
+            "[workspace]\nmembers = [\"talker\", \"listener\", \"util\"]\n",
+        )?;
+        for member in ["talker", "listener", "util"] {
+            std::fs::create_dir_all(workspace.join(member).join("src"))?;
+            std::fs::write(
+                workspace.join(member).join("Cargo.toml"),
+                format!("[package]\nname = \"{member}\"\nversion = \"0.1.0\"\n"),
+            )?;
+            File::create(workspace.join(member).join("src/lib.rs"))?;
+        }
+        // Only the nodes are ROS packages
+        File::create(workspace.join("talker/package.xml"))?;
+        File::create(workspace.join("listener/package.xml"))?;
+        let root_manifest = workspace.join("Cargo.toml").canonicalize()?;
+        let metadata = read_metadata(&root_manifest)?;
+        let names = |packages: Vec<&Package>| -> Vec<String> {
+            let mut names: Vec<_> = packages.iter().map(|p| p.name.clone()).collect();
+            names.sort();
+            names
+        };
+
+        let virtual_manifest = select_packages(&metadata, &root_manifest, &Default::default())?;
+        assert_eq!(names(virtual_manifest), ["listener", "talker"]);
+
+        let targets =
+            TargetSelection::from_args(&["--workspace".into(), "--exclude=talker".into()]);
+        let excluded = select_packages(&metadata, &root_manifest, &targets)?;
+        assert_eq!(names(excluded), ["listener"]);
+
+        // A package in the current directory is selected even without a package.xml
+        let util_manifest = workspace.join("util/Cargo.toml").canonicalize()?;
+        let util = select_packages(&metadata, &util_manifest, &Default::default())?;
+        assert_eq!(names(util), ["util"]);
+
+        let targets = TargetSelection::from_args(&["-p".into(), "util".into()]);
+        assert!(select_packages(&metadata, &root_manifest, &targets).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_package_xmls_of_workspace_members() -> Result<()> {
+        let tmp = tempdir()?;
+        let workspace = tmp.path();
+        std::fs::write(
+            workspace.join("Cargo.toml"),
+            "[workspace]\nmembers = [\"talker\", \"util\"]\n",
+        )?;
+        for member in ["talker", "util"] {
+            std::fs::create_dir_all(workspace.join(member).join("src"))?;
+            std::fs::write(
+                workspace.join(member).join("Cargo.toml"),
+                format!("[package]\nname = \"{member}\"\nversion = \"0.1.0\"\n"),
+            )?;
+            File::create(workspace.join(member).join("src/lib.rs"))?;
+        }
+        File::create(workspace.join("talker/package.xml"))?;
+        let root_manifest = workspace.join("Cargo.toml").canonicalize()?;
+        let metadata = read_metadata(&root_manifest)?;
+        let targets = TargetSelection::from_args(&["--workspace".into()]);
+
+        // The member without a package.xml is selected, so that it can be created
+        let members = select_workspace_members(&metadata, &root_manifest, &targets);
+        let names: Vec<_> = members.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["talker", "util"]);
+        let util = members[1];
+        let path = util.manifest_path.with_file_name("package.xml");
+        assert!(sync_package_xml_file(&path, util, |_| false)?);
+        assert_eq!(PackageXml::read(&path)?.name, "util");
+
+        let targets =
+            TargetSelection::from_args(&["--workspace".into(), "--exclude=talker".into()]);
+        let members = select_workspace_members(&metadata, &root_manifest, &targets);
+        assert_eq!(members.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_packages_in_dependency_order() -> Result<()> {
+        let tmp = tempdir()?;
+        let workspace = tmp.path();
+        std::fs::write(
+            workspace.join("Cargo.toml"),
+            "[workspace]\nmembers = [\"a_node\", \"b_lib\"]\n",
+        )?;
+        for (member, dependencies) in [("a_node", "b_lib = { path = \"../b_lib\" }"), ("b_lib", "")]
+        {
+            std::fs::create_dir_all(workspace.join(member).join("src"))?;
+            std::fs::write(
+                workspace.join(member).join("Cargo.toml"),
+                format!(
+                    "[package]\nname = \"{member}\"\nversion = \"0.1.0\"\n\n[dependencies]\n{dependencies}\n"
+                ),
+            )?;
+            File::create(workspace.join(member).join("src/lib.rs"))?;
+            File::create(workspace.join(member).join("package.xml"))?;
+        }
+        let root_manifest = workspace.join("Cargo.toml").canonicalize()?;
+        let metadata = read_metadata(&root_manifest)?;
+
+        let packages = select_packages(&metadata, &root_manifest, &Default::default())?;
+        let names: Vec<_> = packages
+            .iter()
+            .map(|package| package.name.as_str())
+            .collect();
+        assert_eq!(names, ["b_lib", "a_node"]);
+        Ok(())
+    }
+
     #[test]
     fn test_create_package_marker() -> Result<()> {
         let tmp = tempdir()?;
         let install_base = tmp.path();
 
-        create_package_marker(install_base, "packages", "test_package")?;
+        let mut install_manifest = InstallManifest::new(install_base);
+        create_package_marker(
+            install_base,
+            "packages",
+            "test_package",
+            None,
+            &mut install_manifest,
+        )?;
 
         let marker_path =
             install_base.join("share/ament_index/resource_index/packages/test_package");
 
         assert!(marker_path.exists());
         assert!(marker_path.is_file());
+        assert_eq!(
+            install_manifest.paths().collect::<Vec<_>>(),
+            [Path::new(
+                "share/ament_index/resource_index/packages/test_package"
+            )]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_package_marker_with_content() -> Result<()> {
+        let tmp = tempdir()?;
+        let install_base = tmp.path();
+        let marker_path =
+            install_base.join("share/ament_index/resource_index/rosidl_interfaces/test_package");
+
+        let mut install_manifest = InstallManifest::new(install_base);
+        create_package_marker(
+            install_base,
+            "rosidl_interfaces",
+            "test_package",
+            Some("msg/Foo.msg\n"),
+            &mut install_manifest,
+        )?;
+        assert_eq!(std::fs::read_to_string(&marker_path)?, "msg/Foo.msg\n");
+        assert!(!marker_path.with_file_name(".test_package.tmp").exists());
+
+        // A marker with different content from another package is a conflict
+        let mut other_manifest = InstallManifest::new(install_base);
+        assert!(create_package_marker(
+            install_base,
+            "rosidl_interfaces",
+            "test_package",
+            Some("msg/Bar.msg\n"),
+            &mut other_manifest,
+        )
+        .is_err());
+        assert_eq!(std::fs::read_to_string(&marker_path)?, "msg/Foo.msg\n");
+
+        // The package itself may change the content of its own marker
+        let mut reinstall_manifest = InstallManifest::new(install_base);
+        reinstall_manifest.set_previous_install(&install_manifest);
+        create_package_marker(
+            install_base,
+            "rosidl_interfaces",
+            "test_package",
+            Some("msg/Bar.msg\n"),
+            &mut reinstall_manifest,
+        )?;
+        assert_eq!(std::fs::read_to_string(&marker_path)?, "msg/Bar.msg\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_register_dependency_resources() -> Result<()> {
+        let tmp = tempdir()?;
+        let install_base = tmp.path();
+        let package_xml = PackageXml::parse(
+            r#"<package format="3">
+              <name>test_package</name>
+              <version>0.1.0</version>
+              <build_depend>rosidl_runtime_rs</build_depend>
+              <depend>rclrs</depend>
+              <exec_depend>std_msgs</exec_depend>
+            </package>"#,
+        )?;
+
+        let mut install_manifest = InstallManifest::new(install_base);
+        register_dependency_resources(
+            install_base,
+            "test_package",
+            &package_xml,
+            &[
+                PathBuf::from("/ws/install/a"),
+                PathBuf::from("/opt/ros/rolling"),
+            ],
+            &mut install_manifest,
+        )?;
+
+        let resource_index = install_base.join("share/ament_index/resource_index");
+        assert_eq!(
+            std::fs::read_to_string(resource_index.join("package_run_dependencies/test_package"))?,
+            "rclrs;std_msgs"
+        );
+        let separator = if cfg!(windows) { ';' } else { ':' };
+        assert_eq!(
+            std::fs::read_to_string(resource_index.join("parent_prefix_path/test_package"))?,
+            format!("/ws/install/a{separator}/opt/ros/rolling")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_resources() -> Result<()> {
+        let tmp = tempdir()?;
+        let prefixes = [tmp.path().join("a"), tmp.path().join("b")];
+        for (prefix, package_name) in prefixes.iter().zip(["rclrs", "std_msgs"]) {
+            create_package_marker(
+                prefix,
+                "rust_packages",
+                package_name,
+                None,
+                &mut InstallManifest::new(prefix),
+            )?;
+        }
+        create_package_marker(
+            &prefixes[0],
+            "packages",
+            "cpp_package",
+            None,
+            &mut InstallManifest::new(&prefixes[0]),
+        )?;
+
+        let mut names: Vec<_> = find_resources(&prefixes, "rust_packages")?
+            .into_iter()
+            .collect();
+        names.sort();
+        assert_eq!(names, ["rclrs", "std_msgs"]);
         Ok(())
     }
 
@@ -393,10 +1577,109 @@ mod tests {
 
         std::fs::create_dir_all(&dest_dir)?;
 
-        copy(&src_dir, &dest_dir)?;
+        let mut install_manifest = InstallManifest::new(tmp.path());
+        copy(&src_dir, &dest_dir, &mut install_manifest)?;
 
         assert!(dest_dir.join("src_folder/file.txt").exists());
         assert!(dest_dir.join("src_folder/sub/inner.txt").exists());
+        // Only files are recorded, directories are pruned when uninstalling
+        assert_eq!(
+            install_manifest.paths().collect::<Vec<_>>(),
+            [
+                Path::new("dest_folder/src_folder/file.txt"),
+                Path::new("dest_folder/src_folder/sub/inner.txt")
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_collect_artifacts() -> Result<()> {
+        let output = concat!(
+            r#"{"reason":"compiler-artifact","package_id":"path+file:///pkg#0.1.0","manifest_path":"/pkg/Cargo.toml","target":{"kind":["bin"],"crate_types":["bin"],"name":"my_bin","src_path":"/pkg/src/main.rs","edition":"2021","doc":true,"doctest":false,"test":true},"profile":{"opt_level":"0","debuginfo":2,"debug_assertions":true,"overflow_checks":true,"test":false},"features":[],"filenames":["/target/debug/my_bin"],"executable":"/target/debug/my_bin","fresh":false}"#,
+            "\n",
+            "Output of a build script\n",
+            r#"{"reason":"build-finished","success":true}"#,
+            "\n",
+        );
+
+        let artifacts = collect_artifacts(output.as_bytes())?;
+
+        assert_eq!(artifacts.len(), 1);
+        assert_eq!(artifacts[0].target.name, "my_bin");
+        assert_eq!(
+            artifacts[0].executable.as_deref(),
+            Some(Utf8Path::new("/target/debug/my_bin"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_install_docs() -> Result<()> {
+        let tmp = tempdir()?;
+        let doc_dir = tmp.path().join("target/doc");
+        let install_base = tmp.path().join("install");
+        // Another crate of the workspace was documented into the same directory
+        for file in [
+            "my_package/index.html",
+            "other_crate/index.html",
+            "src/my_package/lib.rs.html",
+            "src/other_crate/lib.rs.html",
+            "static.files/rustdoc.css",
+            "crates.js",
+            "search-index.js",
+            "src-files.js",
+            "trait.impl/core/clone/trait.Clone.js",
+            ".lock",
+        ] {
+            let path = doc_dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            File::create(path)?;
+        }
+        let artifacts = [artifact(
+            "my_package",
+            &["lib"],
+            &[doc_dir.join("my_package/index.html")],
+            None,
+        )];
+
+        let mut install_manifest = InstallManifest::new(&install_base);
+        install_docs(
+            &install_base,
+            "my_package",
+            &artifacts,
+            &mut install_manifest,
+        )?;
+
+        let dest_dir = install_base.join("share/my_package/doc/rust");
+        for file in [
+            "my_package/index.html",
+            "src/my_package/lib.rs.html",
+            "static.files/rustdoc.css",
+        ] {
+            assert!(dest_dir.join(file).is_file(), "{file}");
+        }
+        // The files that index all documented crates are not installed
+        for file in [
+            "other_crate",
+            "src/other_crate",
+            "crates.js",
+            "search-index.js",
+            "src-files.js",
+            "trait.impl",
+            ".lock",
+        ] {
+            assert!(!dest_dir.join(file).exists(), "{file}");
+        }
+        assert_eq!(
+            std::fs::read_to_string(
+                install_base.join("share/ament_index/resource_index/rust_docs/my_package")
+            )?,
+            "share/my_package/doc/rust/my_package/index.html"
+        );
+
+        // Without documentation, e.g. when cargo doc skipped the package, nothing is installed
+        assert!(install_docs(&install_base, "my_package", &[], &mut install_manifest).is_err());
         Ok(())
     }
 
@@ -405,39 +1688,35 @@ mod tests {
         let tmp = tempdir()?;
         let build_base = tmp.path().join("target");
         let install_base = tmp.path().join("install");
-        let profile = "debug";
 
-        // Create dummy binaries in build dir. One of them requires a feature that wasn't used during
-        // compilation and should therefore not be installed
-        let bin_dir = build_base.join(profile);
+        // Create dummy binaries in build dir. One of them requires a feature that wasn't used
+        // during compilation and is a leftover from an earlier build, so it should not be installed
+        let bin_dir = build_base.join("debug");
         std::fs::create_dir_all(&bin_dir)?;
         File::create(bin_dir.join("my_bin"))?;
         File::create(bin_dir.join("skipped_bin"))?;
 
-        let mut features = HashSet::new();
-        features.insert("required_feat".to_string());
-
-        let binaries = vec![
-            Product {
-                name: Some("my_bin".to_string()),
-                required_features: vec!["required_feat".to_string()],
-                ..Default::default()
-            },
-            Product {
-                name: Some("skipped_bin".to_string()),
-                required_features: vec!["missing_feat".to_string()],
-                ..Default::default()
-            },
-        ];
+        let package = package(serde_json::json!({
+            "targets": [
+                bin_target("my_bin", &["required_feat"]),
+                bin_target("skipped_bin", &["missing_feat"]),
+            ],
+        }));
+        let features = HashSet::from(["required_feat".to_string()]);
+        let binaries = expected_binaries(&package, &features, &TargetSelection::default());
+        let artifacts = vec![artifact(
+            "my_bin",
+            &["bin"],
+            &[bin_dir.join("my_bin")],
+            Some(bin_dir.join("my_bin")),
+        )];
 
         install_binaries(
             &install_base,
-            &build_base,
             "my_package",
-            profile,
-            None,
-            &features,
             &binaries,
+            &artifacts,
+            &mut InstallManifest::new(&install_base),
         )?;
 
         assert!(install_base.join("lib/my_package/my_bin").exists());
@@ -447,30 +1726,139 @@ mod tests {
     }
 
     #[test]
-    fn test_install_binaries_with_arch() -> Result<()> {
+    fn test_install_binaries_partial_selection() -> Result<()> {
+        let tmp = tempdir()?;
+        let bin_dir = tmp.path().join("target/debug");
+        let install_base = tmp.path().join("install");
+        let dest_dir = install_base.join("lib/my_package");
+        std::fs::create_dir_all(&bin_dir)?;
+        std::fs::create_dir_all(&dest_dir)?;
+        std::fs::write(bin_dir.join("talker"), "new")?;
+        std::fs::write(dest_dir.join("talker"), "previous")?;
+        std::fs::write(dest_dir.join("listener"), "old")?;
+
+        let talker = bin_target("talker", &[]);
+        let artifacts = vec![artifact(
+            "talker",
+            &["bin"],
+            &[bin_dir.join("talker")],
+            Some(bin_dir.join("talker")),
+        )];
+        install_binaries(
+            &install_base,
+            "my_package",
+            &[&talker],
+            &artifacts,
+            &mut InstallManifest::new(&install_base),
+        )?;
+
+        assert_eq!(std::fs::read_to_string(dest_dir.join("talker"))?, "new");
+        // Not selected, so it is kept
+        assert_eq!(std::fs::read_to_string(dest_dir.join("listener"))?, "old");
+        Ok(())
+    }
+
+    #[test]
+    fn test_install_binaries_skips_test_harness() -> Result<()> {
+        let tmp = tempdir()?;
+        let bin_dir = tmp.path().join("target/debug");
+        let install_base = tmp.path().join("install");
+        std::fs::create_dir_all(bin_dir.join("deps"))?;
+        std::fs::write(bin_dir.join("deps/talker-0123456789abcdef"), "tests")?;
+        std::fs::write(bin_dir.join("talker"), "node")?;
+
+        // Cargo reports the unit tests of a binary before the binary itself, e.g. with
+        // `--all-targets`
+        let talker = bin_target("talker", &[]);
+        let harness = bin_dir.join("deps/talker-0123456789abcdef");
+        let mut test_artifact = artifact(
+            "talker",
+            &["bin"],
+            std::slice::from_ref(&harness),
+            Some(harness.clone()),
+        );
+        test_artifact.profile.test = true;
+        let artifacts = vec![
+            test_artifact,
+            artifact(
+                "talker",
+                &["bin"],
+                &[bin_dir.join("talker")],
+                Some(bin_dir.join("talker")),
+            ),
+        ];
+
+        install_binaries(
+            &install_base,
+            "my_package",
+            &[&talker],
+            &artifacts,
+            &mut InstallManifest::new(&install_base),
+        )?;
+
+        let dest_dir = install_base.join("lib/my_package");
+        assert_eq!(std::fs::read_to_string(dest_dir.join("talker"))?, "node");
+        assert!(!dest_dir.join("talker-0123456789abcdef").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_install_binaries_missing_binary() -> Result<()> {
+        let tmp = tempdir()?;
+        let install_base = tmp.path().join("install");
+        let binary = bin_target("my_bin", &[]);
+        // Only checked, so there is no executable
+        let artifacts = vec![artifact(
+            "my_bin",
+            &["bin"],
+            &[tmp.path().join("target/debug/deps/my_bin.rmeta")],
+            None,
+        )];
+
+        let result = install_binaries(
+            &install_base,
+            "my_package",
+            &[&binary],
+            &artifacts,
+            &mut InstallManifest::new(&install_base),
+        );
+
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_install_binaries_custom_target_dir() -> Result<()> {
         let tmp = tempdir()?;
         let build_base = tmp.path().join("target");
         let install_base = tmp.path().join("install");
         let package_name = "arch_test";
-        let profile = "debug";
-        let arch = "x86_64-unknown-linux-gnu";
 
-        let src_dir_x86 = build_base.join(arch).join(profile);
-        let src_dir_aarch = build_base.join("aarch64-unknown-linux-gnu").join(profile);
+        // Artifacts are taken from wherever cargo put them, e.g. a shared target dir with a
+        // custom profile
+        let src_dir_x86 = tmp
+            .path()
+            .join("shared_target/x86_64-unknown-linux-gnu/custom");
+        let src_dir_aarch = build_base.join("aarch64-unknown-linux-gnu/debug");
         std::fs::create_dir_all(&src_dir_x86)?;
         std::fs::create_dir_all(&src_dir_aarch)?;
 
         std::fs::write(src_dir_x86.join("libarch_test.so"), "x86")?;
         std::fs::write(src_dir_aarch.join("libarch_test.so"), "aarch")?;
 
+        let artifacts = vec![artifact(
+            package_name,
+            &["cdylib"],
+            &[src_dir_x86.join("libarch_test.so")],
+            None,
+        )];
+
         install_binaries(
             &install_base,
-            &build_base,
             package_name,
-            profile,
-            Some(arch),
-            &HashSet::new(),
             &[],
+            &artifacts,
+            &mut InstallManifest::new(&install_base),
         )?;
 
         let dest_file = install_base
@@ -492,31 +1880,56 @@ mod tests {
         let build_base = tmp.path().join("target");
         let install_base = tmp.path().join("install");
         let package_name = "my_rust_lib";
-        let profile = "release";
 
-        let src_dir = build_base.join(profile);
+        let src_dir = build_base.join("release");
         std::fs::create_dir_all(&src_dir)?;
 
-        let so_path = src_dir.join("libmy_rust_lib.so");
-        let a_path = src_dir.join("libmy_rust_lib.a");
-        let dll_path = src_dir.join("my_rust_lib.dll");
-        let lib_path = src_dir.join("my_rust_lib.lib");
-        let dylib_path = src_dir.join("libmy_rust_lib.dylib");
+        let filenames = [
+            "libmy_rust_lib.so",
+            "libmy_rust_lib.a",
+            "my_rust_lib.dll",
+            "my_rust_lib.lib",
+            "libmy_rust_lib.dylib",
+            "libmy_rust_lib.rlib",
+            "my_rust_lib.pdb",
+        ]
+        .map(|filename| src_dir.join(filename));
+        for filename in &filenames {
+            File::create(filename)?;
+        }
 
-        File::create(&so_path)?;
-        File::create(&a_path)?;
-        File::create(&dll_path)?;
-        File::create(&lib_path)?;
-        File::create(&dylib_path)?;
+        // Cargo reports examples with their crate types, but not as library targets
+        let mut example = artifact(
+            "plugin",
+            &["example"],
+            &[src_dir.join("examples/libplugin.so")],
+            None,
+        );
+        example.target.crate_types = vec!["cdylib".into()];
+        let artifacts = vec![
+            artifact(
+                package_name,
+                &["rlib", "cdylib", "staticlib"],
+                &filenames,
+                None,
+            ),
+            // Libraries of other crate types are not installed
+            artifact(
+                "other_lib",
+                &["lib"],
+                &[src_dir.join("libother_lib.rlib")],
+                None,
+            ),
+            // Neither are examples, even if they are built as shared libraries
+            example,
+        ];
 
         install_binaries(
             &install_base,
-            &build_base,
             package_name,
-            profile,
-            None,
-            &HashSet::new(),
             &[],
+            &artifacts,
+            &mut InstallManifest::new(&install_base),
         )?;
 
         let dest_dir = install_base.join("lib").join(package_name);
@@ -526,6 +1939,10 @@ mod tests {
         assert!(dest_dir.join("my_rust_lib.dll").exists());
         assert!(dest_dir.join("my_rust_lib.lib").exists());
         assert!(dest_dir.join("libmy_rust_lib.dylib").exists());
+        assert!(!dest_dir.join("libmy_rust_lib.rlib").exists());
+        assert!(!dest_dir.join("my_rust_lib.pdb").exists());
+        assert!(!dest_dir.join("libother_lib.rlib").exists());
+        assert!(!dest_dir.join("libplugin.so").exists());
 
         Ok(())
     }
@@ -545,16 +1962,299 @@ mod tests {
         install_to_share = ["launch"]
         ```
         */
-        let mut metadata_table_entries: HashMap<String, Value> = HashMap::new();
-        metadata_table_entries.insert(
-            String::from("ros"),
-            Value::from(HashMap::from([("install_to_share", vec!["launch"])])),
-        );
-        let metadata_table = cargo_manifest::Value::from(metadata_table_entries);
+        let metadata = serde_json::json!({
+            "ros": {
+                "install_to_share": ["launch"],
+            },
+        });
 
-        install_files_from_metadata(&install_base, &package_path, "pkg", Some(&metadata_table))?;
+        install_files_from_metadata(
+            &install_base,
+            &package_path,
+            "pkg",
+            &metadata,
+            InstallMode::Copy,
+            &mut InstallManifest::new(&install_base),
+        )?;
 
         assert!(install_base.join("share/pkg/launch/robot.py").exists());
         Ok(())
     }
+
+    #[test]
+    fn test_metadata_install_entries() -> Result<()> {
+        // Entries after a missing one are read as well
+        let metadata = serde_json::json!({
+            "ros": {
+                "install_to_lib": ["scripts"],
+            },
+        });
+        assert_eq!(
+            metadata_install_entries(&metadata)?,
+            [("lib", vec![String::from("scripts")])]
+        );
+
+        let metadata = serde_json::json!({
+            "ros": {
+                "install_to_share": "launch",
+            },
+        });
+        assert!(metadata_install_entries(&metadata).is_err());
+        assert!(metadata_install_entries(&serde_json::json!({}))?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_register_resources_from_metadata() -> Result<()> {
+        let tmp = tempdir()?;
+        let package_path = tmp.path().join("pkg");
+        let install_base = tmp.path().join("install");
+        std::fs::create_dir_all(&package_path)?;
+        std::fs::write(package_path.join("plugins.xml"), "<library/>")?;
+
+        let metadata = serde_json::json!({
+            "ros": {
+                "resources": {
+                    "rviz_common__pluginlib__plugin": { "file": "plugins.xml" },
+                    "my_resource": "share/pkg/config",
+                },
+            },
+        });
+        let mut install_manifest = InstallManifest::new(&install_base);
+        install_files_from_metadata(
+            &install_base,
+            &package_path,
+            "pkg",
+            &metadata,
+            InstallMode::Copy,
+            &mut install_manifest,
+        )?;
+
+        let index = install_base.join("share/ament_index/resource_index");
+        assert_eq!(
+            std::fs::read_to_string(index.join("rviz_common__pluginlib__plugin/pkg"))?,
+            "<library/>"
+        );
+        assert_eq!(
+            std::fs::read_to_string(index.join("my_resource/pkg"))?,
+            "share/pkg/config"
+        );
+        assert!(install_manifest.contains("share/ament_index/resource_index/my_resource/pkg"));
+
+        for invalid in [
+            serde_json::json!({ "../escape": "" }),
+            serde_json::json!({ "packages": "" }),
+            serde_json::json!({ "my_resource": { "file": "missing.xml" } }),
+            serde_json::json!({ "my_resource": { "content": "" } }),
+        ] {
+            let metadata = serde_json::json!({ "ros": { "resources": invalid } });
+            assert!(install_files_from_metadata(
+                &install_base,
+                &package_path,
+                "pkg",
+                &metadata,
+                InstallMode::Copy,
+                &mut install_manifest,
+            )
+            .is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_incremental_copy() -> Result<()> {
+        let tmp_dir = tempdir()?;
+        // The paths must be canonical to detect unchanged files
+        let tmp = tmp_dir.path().canonicalize()?;
+        let src = tmp.join("src/mesh.stl");
+        let install_base = tmp.join("install");
+        let dest_dir = install_base.join("share/pkg");
+        std::fs::create_dir_all(src.parent().unwrap())?;
+        std::fs::create_dir_all(&dest_dir)?;
+        std::fs::write(&src, "solid a")?;
+
+        let mut install_manifest = InstallManifest::new(&install_base);
+        copy(&src, &dest_dir, &mut install_manifest)?;
+        assert!(!install_manifest.is_unchanged("share/pkg/mesh.stl"));
+
+        let mut install_manifest = InstallManifest::new(&install_base);
+        copy(&src, &dest_dir, &mut install_manifest)?;
+        assert!(install_manifest.is_unchanged("share/pkg/mesh.stl"));
+
+        // Only the modification time changed, so the contents are equal
+        let touched = std::time::SystemTime::now() + std::time::Duration::from_secs(10);
+        File::options()
+            .write(true)
+            .open(&src)?
+            .set_modified(touched)?;
+        let mut install_manifest = InstallManifest::staged(&install_base, &install_base, true);
+        copy(&src, &dest_dir, &mut install_manifest)?;
+        assert!(install_manifest.is_unchanged("share/pkg/mesh.stl"));
+        // Without comparing contents, the file is copied again
+        let mut install_manifest = InstallManifest::new(&install_base);
+        copy(&src, &dest_dir, &mut install_manifest)?;
+        assert!(!install_manifest.is_unchanged("share/pkg/mesh.stl"));
+
+        std::fs::write(&src, "solid b")?;
+        let mut install_manifest = InstallManifest::staged(&install_base, &install_base, true);
+        copy(&src, &dest_dir, &mut install_manifest)?;
+        assert!(!install_manifest.is_unchanged("share/pkg/mesh.stl"));
+        assert_eq!(
+            std::fs::read_to_string(dest_dir.join("mesh.stl"))?,
+            "solid b"
+        );
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_switch_staged_install_to_symlinks() -> Result<()> {
+        let tmp = tempdir()?;
+        let package_path = tmp.path().join("pkg");
+        let install_base = tmp.path().join("install");
+        let installed = install_base.join("share/pkg/launch");
+
+        std::fs::create_dir_all(package_path.join("launch"))?;
+        File::create(package_path.join("launch/robot.py"))?.write_all(b"launch")?;
+        let metadata = serde_json::json!({
+            "ros": {
+                "install_to_share": ["launch"],
+            },
+        });
+
+        // Installs like the plugin does, removing what the previous install had installed
+        let install = |mode: InstallMode, previous: &InstallManifest| -> Result<InstallManifest> {
+            let staging = StagedInstall::new(&install_base, "pkg")?;
+            let mut staged = staging.manifest(false);
+            staged.set_previous_install(previous);
+            install_files_from_metadata(
+                staging.path(),
+                &package_path,
+                "pkg",
+                &metadata,
+                mode,
+                &mut staged,
+            )?;
+            let outdated: Vec<_> = previous.paths().map(Path::to_owned).collect();
+            staging.commit(&staged, &outdated)?;
+            Ok(staged)
+        };
+
+        let copied = install(InstallMode::Copy, &InstallManifest::new(&install_base))?;
+        assert!(installed.join("robot.py").is_file());
+        assert!(!installed.symlink_metadata()?.is_symlink());
+
+        let symlinked = install(InstallMode::Symlink, &copied)?;
+        assert!(installed.symlink_metadata()?.is_symlink());
+        assert_eq!(
+            std::fs::read_link(&installed)?,
+            package_path.join("launch").canonicalize()?
+        );
+
+        install(InstallMode::Copy, &symlinked)?;
+        assert!(!installed.symlink_metadata()?.is_symlink());
+        assert_eq!(std::fs::read(installed.join("robot.py"))?, b"launch");
+        Ok(())
+    }
+
+    #[test]
+    fn test_staged_install_keeps_empty_directories() -> Result<()> {
+        let tmp = tempdir()?;
+        let package_path = tmp.path().join("pkg");
+        let install_base = tmp.path().join("install");
+        let installed = install_base.join("share/pkg/config");
+
+        std::fs::create_dir_all(package_path.join("config"))?;
+        let install =
+            |metadata: serde_json::Value, previous: &InstallManifest| -> Result<InstallManifest> {
+                let staging = StagedInstall::new(&install_base, "pkg")?;
+                let mut staged = staging.manifest(false);
+                staged.set_previous_install(previous);
+                install_files_from_metadata(
+                    staging.path(),
+                    &package_path,
+                    "pkg",
+                    &metadata,
+                    InstallMode::Copy,
+                    &mut staged,
+                )?;
+                let outdated: Vec<_> = previous.paths().map(Path::to_owned).collect();
+                staging.commit(&staged, &outdated)?;
+                Ok(staged)
+            };
+        let metadata = serde_json::json!({
+            "ros": {
+                "install_to_share": ["config"],
+            },
+        });
+
+        let first = install(metadata.clone(), &InstallManifest::new(&install_base))?;
+        assert!(installed.is_dir());
+        let second = install(metadata, &first)?;
+        assert!(second.is_unchanged("share/pkg/config"));
+        assert!(installed.is_dir());
+
+        // The directory is removed once it is no longer installed
+        install(serde_json::json!({}), &second)?;
+        assert!(!installed.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_symlink_install() -> Result<()> {
+        let tmp = tempdir()?;
+        let package_path = tmp.path().join("pkg");
+        let install_base = tmp.path().join("install");
+        let installed = install_base.join("share/pkg/launch");
+
+        std::fs::create_dir_all(package_path.join("launch"))?;
+        File::create(package_path.join("launch/robot.py"))?.write_all(b"launch")?;
+        let metadata = serde_json::json!({
+            "ros": {
+                "install_to_share": ["launch"],
+            },
+        });
+
+        install_files_from_metadata(
+            &install_base,
+            &package_path,
+            "pkg",
+            &metadata,
+            InstallMode::Symlink,
+            &mut InstallManifest::new(&install_base),
+        )?;
+        assert!(installed.symlink_metadata()?.is_symlink());
+        assert_eq!(
+            std::fs::read_link(&installed)?,
+            package_path.join("launch").canonicalize()?
+        );
+
+        // Installing again, e.g. after touching the source, must replace the symlink
+        install_files_from_metadata(
+            &install_base,
+            &package_path,
+            "pkg",
+            &metadata,
+            InstallMode::Symlink,
+            &mut InstallManifest::new(&install_base),
+        )?;
+        assert!(installed.symlink_metadata()?.is_symlink());
+
+        // Switching back to copying must not write through the symlink into the source tree
+        install_files_from_metadata(
+            &install_base,
+            &package_path,
+            "pkg",
+            &metadata,
+            InstallMode::Copy,
+            &mut InstallManifest::new(&install_base),
+        )?;
+        assert!(!installed.symlink_metadata()?.is_symlink());
+        assert_eq!(std::fs::read(installed.join("robot.py"))?, b"launch");
+        assert_eq!(
+            std::fs::read(package_path.join("launch/robot.py"))?,
+            b"launch"
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,425 @@
+// Licensed under the Apache License, Version 2.0
+
+use anyhow::{bail, Context, Result};
+use cargo_metadata::diagnostic::{Diagnostic, DiagnosticLevel};
+use cargo_metadata::{CompilerMessage, Message, Package};
+use serde_json::Value;
+
+use crate::{TestCase, TestOutcome, TestSuite};
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// The lint settings of a package, from `[package.metadata.ros.lint]`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LintConfig {
+    /// Whether clippy warnings and formatting differences fail the lint tests, like any finding
+    /// of ament_lint does. Compiler errors always fail them.
+    pub fail_on_warnings: bool,
+}
+
+impl Default for LintConfig {
+    fn default() -> Self {
+        Self {
+            fail_on_warnings: true,
+        }
+    }
+}
+
+impl LintConfig {
+    pub fn from_package(package: &Package) -> Result<Self> {
+        let mut config = Self::default();
+        let Some(lint) = package.metadata.get("ros").and_then(|ros| ros.get("lint")) else {
+            return Ok(config);
+        };
+        if let Some(fail_on_warnings) = lint.get("fail_on_warnings") {
+            let Value::Bool(fail_on_warnings) = fail_on_warnings else {
+                bail!("Expected a boolean for 'package.metadata.ros.lint.fail_on_warnings'");
+            };
+            config.fail_on_warnings = *fail_on_warnings;
+        }
+        Ok(config)
+    }
+}
+
+/// The outcome of `cargo clippy`.
+pub struct ClippyOutput {
+    /// The exit code of cargo. None if it was terminated by a signal.
+    pub exit_code: Option<i32>,
+    pub messages: Vec<CompilerMessage>,
+}
+
+/// Runs `cargo clippy` and collects the warnings and errors it reports.
+pub fn cargo_clippy(args: &[OsString]) -> Result<ClippyOutput> {
+    let mut cmd = Command::new("cargo");
+    cmd.arg("clippy");
+    // With json-render-diagnostics, cargo would leave the diagnostics out of the JSON messages
+    cmd.arg("--message-format=json");
+    cmd.args(args);
+    cmd.stdout(Stdio::piped());
+    let mut child = cmd
+        .spawn()
+        .context("Failed to spawn 'cargo clippy' subprocess")?;
+    // unwrap is ok since stdout is piped
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let messages = collect_compiler_messages(stdout)?;
+    let exit_status = child
+        .wait()
+        .context("Failed to wait for 'cargo clippy' subprocess")?;
+    Ok(ClippyOutput {
+        exit_code: exit_status.code(),
+        messages,
+    })
+}
+
+/// Collects the warnings and errors from cargo's JSON output.
+///
+/// The diagnostics are printed to stderr like cargo would print them. Diagnostics that are
+/// reported for several targets of a package, e.g. for the library and its unit tests, are only
+/// collected once. Lines that are not JSON messages are passed through.
+fn collect_compiler_messages(reader: impl BufRead) -> Result<Vec<CompilerMessage>> {
+    let mut messages: Vec<CompilerMessage> = Vec::new();
+    let mut seen = HashSet::new();
+    for message in Message::parse_stream(reader) {
+        match message.context("Failed to read the output of cargo")? {
+            Message::CompilerMessage(message) => {
+                if let Some(rendered) = &message.message.rendered {
+                    eprint!("{rendered}");
+                }
+                if !matches!(
+                    message.message.level,
+                    DiagnosticLevel::Error | DiagnosticLevel::Ice | DiagnosticLevel::Warning
+                ) || is_summary(&message.message)
+                {
+                    continue;
+                }
+                if seen.insert((message.package_id.clone(), message.message.rendered.clone())) {
+                    messages.push(message);
+                }
+            }
+            Message::TextLine(line) => println!("{line}"),
+            _ => {}
+        }
+    }
+    Ok(messages)
+}
+
+/// Whether a diagnostic only summarizes others, like "aborting due to 2 previous errors".
+fn is_summary(diagnostic: &Diagnostic) -> bool {
+    diagnostic.spans.is_empty()
+        && diagnostic.code.is_none()
+        && (diagnostic.message.starts_with("aborting due to")
+            || diagnostic.message.ends_with(" emitted"))
+}
+
+/// A file that is not formatted like rustfmt would format it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FormatDiff {
+    /// The path of the file, as printed by rustfmt
+    pub file: String,
+    /// The differences to the formatted file
+    pub diff: String,
+}
+
+/// The outcome of `cargo fmt --check` for one package.
+pub struct FormatCheckOutput {
+    /// The exit code of cargo. None if it was terminated by a signal.
+    pub exit_code: Option<i32>,
+    pub diffs: Vec<FormatDiff>,
+    /// The error output, which explains failures that are not formatting differences
+    pub stderr: String,
+}
+
+/// Runs `cargo fmt --check` for a package of the workspace.
+///
+/// This takes the manifest path and package name instead of the forwarded arguments, since
+/// `cargo fmt` does not understand the options of `cargo build`.
+pub fn cargo_fmt_check(
+    manifest_path: impl AsRef<Path>,
+    package_name: &str,
+) -> Result<FormatCheckOutput> {
+    let output = Command::new("cargo")
+        .arg("fmt")
+        .arg("--check")
+        .arg("--manifest-path")
+        .arg(manifest_path.as_ref())
+        .args(["--package", package_name, "--", "--color", "never"])
+        .output()
+        .context("Failed to run 'cargo fmt'")?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    print!("{stdout}");
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    eprint!("{stderr}");
+    Ok(FormatCheckOutput {
+        exit_code: output.status.code(),
+        diffs: parse_rustfmt_diffs(&stdout),
+        stderr,
+    })
+}
+
+/// Parses the output of `rustfmt --check` into one diff per file.
+///
+/// Each hunk starts with a line like `Diff in /ws/src/lib.rs:12:`, or with
+/// `Diff in /ws/src/lib.rs at line 12:` in older versions of rustfmt.
+pub fn parse_rustfmt_diffs(output: &str) -> Vec<FormatDiff> {
+    let mut diffs: Vec<FormatDiff> = Vec::new();
+    for line in output.lines() {
+        if let Some(location) = line
+            .strip_prefix("Diff in ")
+            .and_then(|rest| rest.strip_suffix(':'))
+        {
+            let file = match location.rsplit_once(" at line ") {
+                Some((file, _)) => file,
+                None => location
+                    .rsplit_once(':')
+                    .filter(|(_, line)| line.chars().all(|c| c.is_ascii_digit()))
+                    .map_or(location, |(file, _)| file),
+            };
+            if diffs.last().is_none_or(|diff| diff.file != file) {
+                diffs.push(FormatDiff {
+                    file: file.to_owned(),
+                    diff: String::new(),
+                });
+            }
+        }
+        if let Some(diff) = diffs.last_mut() {
+            diff.diff.push_str(line);
+            diff.diff.push('\n');
+        }
+    }
+    diffs
+}
+
+/// Converts the lint findings of a package into test suites, with one test case per finding.
+///
+/// A tool without findings gets a single passing test case, so that colcon counts it. Findings
+/// that do not fail the tests are kept as passing test cases with their output.
+pub fn lint_suites(
+    package: &Package,
+    messages: &[CompilerMessage],
+    format_check: &FormatCheckOutput,
+    config: LintConfig,
+) -> Vec<TestSuite> {
+    let warning_outcome = if config.fail_on_warnings {
+        TestOutcome::Failed
+    } else {
+        TestOutcome::Passed
+    };
+    let package_dir = package.manifest_path.parent().map(|dir| dir.as_std_path());
+
+    let mut clippy_cases: Vec<_> = messages
+        .iter()
+        .filter(|message| message.package_id == package.id)
+        .map(|message| {
+            let diagnostic = &message.message;
+            let name = diagnostic
+                .code
+                .as_ref()
+                .map_or(diagnostic.message.as_str(), |code| code.code.as_str());
+            let location = diagnostic
+                .spans
+                .iter()
+                .find(|span| span.is_primary)
+                .map(|span| {
+                    format!(
+                        " ({}:{}:{})",
+                        span.file_name, span.line_start, span.column_start
+                    )
+                })
+                .unwrap_or_default();
+            let outcome = match diagnostic.level {
+                DiagnosticLevel::Warning => warning_outcome,
+                _ => TestOutcome::Failed,
+            };
+            TestCase {
+                name: format!("{name}{location}"),
+                outcome,
+                output: diagnostic.to_string().trim_end().to_owned(),
+                time: None,
+            }
+        })
+        .collect();
+    if clippy_cases.is_empty() {
+        clippy_cases.push(passed("clippy"));
+    }
+
+    let mut rustfmt_cases: Vec<_> = format_check
+        .diffs
+        .iter()
+        .map(|diff| {
+            let file = package_dir
+                .and_then(|dir| Path::new(&diff.file).strip_prefix(dir).ok())
+                .map_or(diff.file.clone(), |file| file.display().to_string());
+            TestCase {
+                name: format!("rustfmt ({file})"),
+                outcome: warning_outcome,
+                output: diff.diff.trim_end().to_owned(),
+                time: None,
+            }
+        })
+        .collect();
+    if rustfmt_cases.is_empty() {
+        rustfmt_cases.push(match format_check.exit_code {
+            Some(0) => passed("rustfmt"),
+            // E.g. a file that cannot be parsed
+            _ => TestCase {
+                name: "rustfmt".to_owned(),
+                outcome: TestOutcome::Failed,
+                output: format_check.stderr.trim_end().to_owned(),
+                time: None,
+            },
+        });
+    }
+
+    vec![
+        TestSuite {
+            name: "lints".to_owned(),
+            crate_name: "clippy".to_owned(),
+            cases: clippy_cases,
+        },
+        TestSuite {
+            name: "formatting".to_owned(),
+            crate_name: "rustfmt".to_owned(),
+            cases: rustfmt_cases,
+        },
+    ]
+}
+
+fn passed(name: &str) -> TestCase {
+    TestCase {
+        name: name.to_owned(),
+        outcome: TestOutcome::Passed,
+        output: String::new(),
+        time: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::package;
+    use serde_json::json;
+
+    fn compiler_message(level: &str, code: Option<&str>, message: &str) -> String {
+        json!({
+            "reason": "compiler-message",
+            "package_id": "path+file:///ws/my_package#0.1.0",
+            "manifest_path": "/ws/my_package/Cargo.toml",
+            "target": { "kind": ["lib"], "crate_types": ["lib"], "name": "my_package",
+                        "src_path": "/ws/my_package/src/lib.rs" },
+            "message": {
+                "message": message,
+                "code": code.map(|code| json!({ "code": code, "explanation": null })),
+                "level": level,
+                "spans": if code.is_some() {
+                    json!([{ "file_name": "src/lib.rs", "byte_start": 10, "byte_end": 18,
+                             "line_start": 7, "line_end": 7, "column_start": 28,
+                             "column_end": 36, "is_primary": true, "text": [], "label": null,
+                             "suggested_replacement": null, "suggestion_applicability": null,
+                             "expansion": null }])
+                } else {
+                    json!([])
+                },
+                "children": [],
+                "rendered": format!("{level}: {message}\n"),
+            },
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_lint_suites() -> Result<()> {
+        let output = [
+            compiler_message(
+                "warning",
+                Some("clippy::needless_return"),
+                "unneeded `return`",
+            ),
+            // The same warning for the unit tests of the library
+            compiler_message(
+                "warning",
+                Some("clippy::needless_return"),
+                "unneeded `return`",
+            ),
+            compiler_message("error", Some("E0308"), "mismatched types"),
+            compiler_message("error", None, "aborting due to 1 previous error"),
+            "Not a JSON message".to_owned(),
+        ]
+        .join("\n");
+        let messages = collect_compiler_messages(output.as_bytes())?;
+        assert_eq!(messages.len(), 2);
+
+        let format_check = FormatCheckOutput {
+            exit_code: Some(1),
+            diffs: parse_rustfmt_diffs(
+                "Diff in /ws/my_package/src/lib.rs:3:\n-fn f( ) {}\n+fn f() {}\n\
+                 Diff in /ws/my_package/src/lib.rs:9:\n-x\n+y\n\
+                 Diff in /ws/my_package/src/main.rs at line 1:\n-a\n+b\n",
+            ),
+            stderr: String::new(),
+        };
+        assert_eq!(format_check.diffs.len(), 2);
+        assert_eq!(format_check.diffs[0].diff.lines().count(), 6);
+
+        let package = package(json!({}));
+        let suites = lint_suites(&package, &messages, &format_check, LintConfig::default());
+        let clippy = &suites[0].cases;
+        assert_eq!(clippy[0].name, "clippy::needless_return (src/lib.rs:7:28)");
+        assert_eq!(clippy[0].outcome, TestOutcome::Failed);
+        assert_eq!(clippy[0].output, "warning: unneeded `return`");
+        assert_eq!(clippy[1].name, "E0308 (src/lib.rs:7:28)");
+        let rustfmt = &suites[1].cases;
+        assert_eq!(rustfmt[0].name, "rustfmt (src/lib.rs)");
+        assert_eq!(rustfmt[1].name, "rustfmt (src/main.rs)");
+
+        // Only errors fail when warnings are allowed
+        let package = package_with_lint(json!({ "fail_on_warnings": false }));
+        let config = LintConfig::from_package(&package)?;
+        assert!(!config.fail_on_warnings);
+        let suites = lint_suites(&package, &messages, &format_check, config);
+        let outcomes: Vec<_> = suites
+            .iter()
+            .flat_map(|suite| &suite.cases)
+            .map(|case| case.outcome)
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                TestOutcome::Passed,
+                TestOutcome::Failed,
+                TestOutcome::Passed,
+                TestOutcome::Passed
+            ]
+        );
+
+        // Without findings, each tool gets a passing test case
+        let format_check = FormatCheckOutput {
+            exit_code: Some(0),
+            diffs: Vec::new(),
+            stderr: String::new(),
+        };
+        let suites = lint_suites(&package, &[], &format_check, config);
+        assert_eq!(suites[0].cases, [passed("clippy")]);
+        assert_eq!(suites[1].cases, [passed("rustfmt")]);
+        Ok(())
+    }
+
+    fn package_with_lint(lint: Value) -> Package {
+        package(json!({ "metadata": { "ros": { "lint": lint } } }))
+    }
+
+    #[test]
+    fn test_lint_config() {
+        assert_eq!(
+            LintConfig::from_package(&package(json!({}))).unwrap(),
+            LintConfig::default()
+        );
+        assert!(
+            LintConfig::from_package(&package_with_lint(json!({ "fail_on_warnings": "no" })))
+                .is_err()
+        );
+    }
+}
@@ -1,9 +1,13 @@
 // Licensed under the Apache License, Version 2.0
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 
 use cargo_ament_build::*;
-use cargo_manifest::Manifest;
+use cargo_metadata::{Artifact, Metadata, Package};
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
 
 fn main() {
     let exitcode = match fallible_main().context("Error in cargo-ament-build") {
@@ -24,84 +28,615 @@ fn main() {
 fn fallible_main() -> Result<bool> {
     let args = match ArgsOrHelp::parse()? {
         ArgsOrHelp::Args(args) => args,
+        ArgsOrHelp::Init(options) => {
+            init_package(&options)?;
+            println!(
+                "Created package '{}' in '{}'",
+                options.name,
+                options.package_path().display()
+            );
+            return Ok(true);
+        }
         ArgsOrHelp::Help => {
             ArgsOrHelp::print_help();
             return Ok(true);
         }
     };
-    let mut manifest = Manifest::from_path(&args.manifest_path)?;
-    manifest.complete_from_path(&args.manifest_path)?;
-
-    // Unwrap is safe since complete_from_path() has been called
-    let is_pure_library = {
-        let no_binaries = manifest.bin.is_empty();
-        let no_exported_libraries = if let Some(crate_types) = manifest
-            .lib
-            .as_ref()
-            .and_then(|lib| lib.crate_type.as_ref())
-        {
-            crate_types.as_slice() == [String::from("rlib")]
-        } else {
-            true
-        };
-        no_binaries && no_exported_libraries
-    };
-    let verb = if is_pure_library { "check" } else { "build" };
-    let exitcode = cargo(&args.forwarded_args, verb)?
+    match args.subcommand {
+        Subcommand::Build => {}
+        Subcommand::Uninstall => {
+            uninstall(&args)?;
+            return Ok(true);
+        }
+        Subcommand::SyncPackageXml => {
+            sync_package_xmls(&args)?;
+            return Ok(true);
+        }
+        Subcommand::Info => {
+            print_info(&args)?;
+            return Ok(true);
+        }
+        Subcommand::Test => return run_tests(&args),
+        Subcommand::Lint => return run_lints(&args),
+    }
+    let metadata = read_metadata(&args.manifest_path)?;
+    let packages = select_packages(&metadata, &args.manifest_path, &args.targets)?;
+    let is_workspace_build = args.targets.selects_workspace_members()
+        || find_package(&metadata, &args.manifest_path).is_err();
+    for package in &packages {
+        validate_package_xml(package, args.strict_package_xml)
+            .with_context(|| format!("Invalid package.xml in package '{}'", package.name))?;
+    }
+    if args.lint_deps {
+        lint_dependencies(&args, &metadata, &packages)?;
+    }
+
+    let verb = cargo_verb(&packages);
+    let output = cargo(&args.forwarded_args, verb, &[])?;
+    let exitcode = output
+        .exit_code
         .ok_or_else(|| anyhow!("'cargo {}' was terminated by signal.", verb))?;
     if exitcode != 0 {
         return Ok(false);
     }
-    let package = manifest
-        .package
-        .as_ref()
-        .ok_or(anyhow!("Cargo manifest has no package section."))?;
+    let documented: Vec<_> = packages
+        .iter()
+        .copied()
+        .filter(|package| args.doc || installs_docs(package))
+        .collect();
+    let doc_artifacts = if documented.is_empty() {
+        Vec::new()
+    } else {
+        let output = cargo(&doc_args(&args, &documented), "doc", &[])?;
+        if output.exit_code != Some(0) {
+            return Ok(false);
+        }
+        output.artifacts
+    };
+    for package in packages {
+        let install_base = package_install_base(&args, is_workspace_build, &package.name);
+        install(
+            &args,
+            &install_base,
+            package,
+            &metadata,
+            &output.artifacts,
+            &doc_artifacts,
+        )
+        .with_context(|| format!("Failed to install package '{}'", package.name))?;
+    }
+    Ok(true)
+}
+
+/// The arguments for `cargo doc` that document the given packages with the features and target
+/// of the build.
+fn doc_args(args: &Args, packages: &[&Package]) -> Vec<OsString> {
+    let mut doc_args: Vec<OsString> = vec![
+        "--no-deps".into(),
+        "--manifest-path".into(),
+        args.manifest_path.clone().into(),
+        "--target-dir".into(),
+        args.build_base.clone().into(),
+    ];
+    if let Some(arch) = &args.arch {
+        doc_args.push("--target".into());
+        doc_args.push(arch.into());
+    }
+    for package in packages {
+        doc_args.push("--package".into());
+        doc_args.push((&package.name).into());
+    }
+    doc_args.extend(args.features.to_args());
+    doc_args
+}
+
+/// Checks the package.xml of a package against its Cargo.toml, if it has one.
+///
+/// This happens before building, so that mistakes are not only noticed by colcon or bloom later.
+fn validate_package_xml(package: &Package, strict: bool) -> Result<()> {
+    let package_xml_path = package.manifest_path.with_file_name("package.xml");
+    if !package_xml_path.exists() {
+        return Ok(());
+    }
+    for warning in PackageXml::read(&package_xml_path)?.validate(package, strict)? {
+        eprintln!("Warning in '{package_xml_path}': {warning}");
+    }
+    Ok(())
+}
+
+/// The names of the ROS Rust packages that packages of the workspace can depend on.
+///
+/// These are the packages registered under `rust_packages` in the install base or an underlay,
+/// and the workspace members with a package.xml, which may not be installed yet.
+fn rust_packages(args: &Args, metadata: &Metadata) -> Result<HashSet<String>> {
+    // The install base is empty when syncing package.xml files without one
+    let mut prefixes: Vec<_> = Some(args.install_base.clone())
+        .filter(|install_base| !install_base.as_os_str().is_empty())
+        .into_iter()
+        .collect();
+    if let Some(ament_prefix_path) = std::env::var_os("AMENT_PREFIX_PATH") {
+        prefixes.extend(std::env::split_paths(&ament_prefix_path));
+    }
+    let mut rust_packages = find_resources(&prefixes, "rust_packages")?;
+    rust_packages.extend(
+        metadata
+            .workspace_packages()
+            .into_iter()
+            .filter(|package| package.manifest_path.with_file_name("package.xml").exists())
+            .map(|package| package.name.clone()),
+    );
+    Ok(rust_packages)
+}
+
+/// Compares the package.xml dependencies of the packages with their Cargo dependencies.
+fn lint_dependencies(args: &Args, metadata: &Metadata, packages: &[&Package]) -> Result<()> {
+    let rust_packages = rust_packages(args, metadata)?;
+    let mut problems = Vec::new();
+    for package in packages {
+        let package_xml_path = package.manifest_path.with_file_name("package.xml");
+        if !package_xml_path.exists() {
+            continue;
+        }
+        let package_xml = PackageXml::read(&package_xml_path)?;
+        problems.extend(
+            package_xml
+                .lint_dependencies(package, |name| rust_packages.contains(name))
+                .into_iter()
+                .map(|problem| format!("{}: {problem}", package.name)),
+        );
+    }
+    if !problems.is_empty() {
+        bail!(
+            "The package.xml dependencies do not match the Cargo dependencies:\n{}",
+            problems.join("\n")
+        );
+    }
+    Ok(())
+}
+
+/// Runs the tests of the selected packages and writes their results as JUnit XML.
+///
+/// The tests run on a ROS domain of their own, so that tests of packages that are run in
+/// parallel do not interfere. The selected packages share one domain, since their test
+/// executables run one after another. With `--coverage`, the coverage of the tests is written to
+/// `<build_base>/coverage/`. Like for builds, failing tests are not an error of this plugin,
+/// but indicated by the returned boolean.
+fn run_tests(args: &Args) -> Result<bool> {
+    let metadata = read_metadata(&args.manifest_path)?;
+    let packages = select_packages(&metadata, &args.manifest_path, &args.targets)?;
+    let domain_id = DomainIdLock::acquire(DomainIdLock::default_lock_dir())?;
+    let mut envs = vec![("ROS_DOMAIN_ID", domain_id.domain_id().to_string())];
+    if args.localhost_only {
+        // The former is understood up to Humble, the latter from Iron on
+        envs.push(("ROS_LOCALHOST_ONLY", "1".to_owned()));
+        envs.push(("ROS_AUTOMATIC_DISCOVERY_RANGE", "LOCALHOST".to_owned()));
+    }
+    let coverage_dir = args.build_base.join("coverage");
+    let mut test_args = args.forwarded_args.clone();
+    if args.coverage {
+        envs.extend(prepare_coverage(&coverage_dir)?);
+        test_args = coverage_args(&test_args, &coverage_dir);
+    }
+    println!("Running tests with ROS_DOMAIN_ID={}", domain_id.domain_id());
+    let mut success = true;
+    let mut runs = Vec::new();
+    let mut test_executables = Vec::new();
+    // The test executables are run one by one, which tells the package each one belongs to
+    if !args.targets.doc {
+        let mut build_args = test_args.clone();
+        build_args.insert(0, "--no-run".into());
+        let build = cargo(&build_args, "test", &envs)?;
+        if build.exit_code != Some(0) {
+            return Ok(false);
+        }
+        let mut artifacts = build.artifacts;
+        // Like cargo, the unit tests of a package run before its integration tests
+        artifacts.sort_by_key(|artifact| {
+            let target = &artifact.target;
+            let is_test = target.is_test() || target.is_bench();
+            (artifact.manifest_path.clone(), is_test, target.name.clone())
+        });
+        for artifact in artifacts {
+            if !artifact.profile.test || artifact.executable.is_none() {
+                continue;
+            }
+            let package = metadata
+                .packages
+                .iter()
+                .find(|package| package.id == artifact.package_id)
+                .ok_or_else(|| anyhow!("Unknown package '{}'", artifact.package_id))?;
+            let run = run_test_executable(&artifact, package, &test_args, &envs)?;
+            success &= run.success;
+            runs.push(run);
+            // unwrap is ok since only executables are run
+            test_executables.push(PathBuf::from(artifact.executable.unwrap()));
+        }
+    }
+    // Like cargo, doc tests run last, and only when no other targets were selected
+    if args.targets.doc || args.targets.is_default() {
+        for package in &packages {
+            let has_doc_tests = package
+                .targets
+                .iter()
+                .any(|target| target.is_lib() && target.doctest);
+            if has_doc_tests {
+                let run = run_doc_tests(package, &test_args, &envs)?;
+                success &= run.success;
+                runs.push(run);
+            }
+        }
+    }
+    if args.coverage {
+        let package_dirs: Vec<_> = packages
+            .iter()
+            .filter_map(|package| {
+                let dir = package.manifest_path.parent()?;
+                Some((package.name.as_str(), dir.as_std_path()))
+            })
+            .collect();
+        write_coverage_reports(&coverage_dir, &test_executables, &package_dirs)?;
+        println!("Wrote coverage reports to '{}'", coverage_dir.display());
+    }
+    for package in &packages {
+        let suites: Vec<_> = runs
+            .iter()
+            .filter(|run| run.package_id == package.id)
+            .map(|run| &run.suite)
+            .collect();
+        let path = args
+            .build_base
+            .join("test_results")
+            .join(&package.name)
+            .join("cargo_test.xml");
+        write_junit(&path, &package.name, &suites)?;
+    }
+    Ok(success)
+}
+
+/// Runs clippy and rustfmt on the selected packages and writes their findings as JUnit XML.
+///
+/// Like for tests, findings are not an error of this plugin, but indicated by the returned
+/// boolean.
+fn run_lints(args: &Args) -> Result<bool> {
+    let metadata = read_metadata(&args.manifest_path)?;
+    let packages = select_packages(&metadata, &args.manifest_path, &args.targets)?;
+    let configs = packages
+        .iter()
+        .map(|package| {
+            LintConfig::from_package(package)
+                .with_context(|| format!("Invalid lint settings in package '{}'", package.name))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let clippy = cargo_clippy(&args.forwarded_args)?;
+    let exitcode = clippy
+        .exit_code
+        .ok_or_else(|| anyhow!("'cargo clippy' was terminated by signal."))?;
+    let mut success = exitcode == 0;
+    for (package, config) in packages.iter().zip(configs) {
+        let format_check = cargo_fmt_check(&args.manifest_path, &package.name)?;
+        let suites = lint_suites(package, &clippy.messages, &format_check, config);
+        success &= suites
+            .iter()
+            .flat_map(|suite| &suite.cases)
+            .all(|case| case.outcome != TestOutcome::Failed);
+        let path = args
+            .build_base
+            .join("test_results")
+            .join(&package.name)
+            .join("cargo_lint.xml");
+        write_junit(&path, &package.name, &suites.iter().collect::<Vec<_>>())?;
+    }
+    Ok(success)
+}
+
+/// Prints what building and installing the selected packages does, as JSON.
+///
+/// This goes through the same steps as a build, without running cargo.
+fn print_info(args: &Args) -> Result<()> {
+    let metadata = read_metadata(&args.manifest_path)?;
+    let packages = select_packages(&metadata, &args.manifest_path, &args.targets)?;
+    let is_workspace_build = args.targets.selects_workspace_members()
+        || find_package(&metadata, &args.manifest_path).is_err();
+    let mut package_infos = Vec::new();
+    for package in &packages {
+        let install_base = Some(package_install_base(
+            args,
+            is_workspace_build,
+            &package.name,
+        ))
+        .filter(|_| !args.install_base.as_os_str().is_empty());
+        let features = args
+            .features
+            .resolve(package, &workspace_members(&metadata));
+        package_infos.push(package_info(
+            package,
+            &features,
+            &args.targets,
+            install_base.as_deref(),
+            args.doc || installs_docs(package),
+        )?);
+    }
+    let info = serde_json::json!({
+        "verb": cargo_verb(&packages),
+        "packages": package_infos,
+    });
+    println!("{}", serde_json::to_string_pretty(&info)?);
+    Ok(())
+}
+
+/// Updates the package.xml of the selected packages from their Cargo.toml.
+fn sync_package_xmls(args: &Args) -> Result<()> {
+    let metadata = read_metadata(&args.manifest_path)?;
+    // Members without a package.xml are selected as well, to create it
+    let packages = select_workspace_members(&metadata, &args.manifest_path, &args.targets);
+    let mut rust_packages = rust_packages(args, &metadata)?;
+    rust_packages.extend(packages.iter().map(|package| package.name.clone()));
+    for package in packages {
+        let path = package.manifest_path.with_file_name("package.xml");
+        if sync_package_xml_file(&path, package, |name| rust_packages.contains(name))? {
+            println!("Updated '{path}'");
+        }
+    }
+    Ok(())
+}
+
+/// The names of the packages in the workspace.
+fn workspace_members(metadata: &Metadata) -> Vec<&str> {
+    metadata
+        .workspace_packages()
+        .into_iter()
+        .map(|package| package.name.as_str())
+        .collect()
+}
+
+/// The install bases of all packages that are built together, as far as they exist yet.
+///
+/// The paths are canonicalized, since they end up in installed manifests.
+fn workspace_install_bases(args: &Args, metadata: &Metadata) -> Result<Vec<PathBuf>> {
+    let packages = select_packages(metadata, &args.manifest_path, &args.targets)?;
+    let is_workspace_build = args.targets.selects_workspace_members()
+        || find_package(metadata, &args.manifest_path).is_err();
+    Ok(packages
+        .iter()
+        .filter_map(|package| {
+            package_install_base(args, is_workspace_build, &package.name)
+                .canonicalize()
+                .ok()
+        })
+        .collect())
+}
+
+/// The prefix that a package is installed to.
+///
+/// A single package is installed directly to the install base. Packages built together from a
+/// workspace get their own prefix each, like in colcon's isolated install layout.
+fn package_install_base(args: &Args, is_workspace_build: bool, package_name: &str) -> PathBuf {
+    if is_workspace_build && !args.merge_install {
+        args.install_base.join(package_name)
+    } else {
+        args.install_base.clone()
+    }
+}
+
+/// Removes the files recorded in the install manifests of the selected packages.
+fn uninstall(args: &Args) -> Result<()> {
+    // Packages given by name can be uninstalled even after they were removed from the workspace
+    let (package_names, is_workspace_build): (Vec<_>, _) = if args.targets.packages.is_empty() {
+        let metadata = read_metadata(&args.manifest_path)?;
+        let packages = select_packages(&metadata, &args.manifest_path, &args.targets)?;
+        let is_workspace_build = args.targets.selects_workspace_members()
+            || find_package(&metadata, &args.manifest_path).is_err();
+        let names = packages
+            .iter()
+            .map(|package| package.name.clone())
+            .collect();
+        (names, is_workspace_build)
+    } else {
+        let names = args
+            .targets
+            .packages
+            .iter()
+            // unwrap is ok since split always returns at least one element
+            .map(|spec| spec.split('@').next().unwrap().to_owned())
+            .filter(|name| args.targets.selects_package(name))
+            .collect();
+        (names, true)
+    };
+    for package_name in package_names {
+        let install_base = package_install_base(args, is_workspace_build, &package_name);
+        let manifest_path = InstallManifest::location(&args.build_base, &package_name);
+        if !manifest_path.exists() {
+            eprintln!("Package '{package_name}' has no install manifest, skipping it");
+            continue;
+        }
+        InstallManifest::read(&install_base, &manifest_path)?
+            .uninstall()
+            .with_context(|| format!("Failed to uninstall package '{package_name}'"))?;
+        std::fs::remove_file(&manifest_path)?;
+    }
+    Ok(())
+}
+
+/// Installs a package that has been built successfully.
+///
+/// The files are installed to a staging directory first, and only moved to the install base
+/// once all of them were installed, so that a failure does not leave a broken install behind.
+fn install(
+    args: &Args,
+    install_base: &Path,
+    package: &Package,
+    metadata: &Metadata,
+    artifacts: &[Artifact],
+    doc_artifacts: &[Artifact],
+) -> Result<()> {
+    let package_name = &package.name;
+    let manifest_path = InstallManifest::location(&args.build_base, package_name);
+    let mut install_manifest = InstallManifest::read(install_base, &manifest_path)?;
+    let staging = StagedInstall::new(install_base, package_name)?;
+    let mut staged = staging.manifest(args.compare_contents);
+    staged.set_previous_install(&install_manifest);
+    install_files(
+        args,
+        staging.path(),
+        install_base,
+        package,
+        metadata,
+        artifacts,
+        &mut staged,
+    )?;
+    if args.doc || installs_docs(package) {
+        let doc_artifacts: Vec<_> = doc_artifacts
+            .iter()
+            .filter(|artifact| artifact.package_id == package.id)
+            .cloned()
+            .collect();
+        install_docs(staging.path(), package_name, &doc_artifacts, &mut staged)?;
+    }
+
+    // The binaries and libraries that were not selected are kept from the previous install
+    let binary_dir = Path::new("lib").join(package_name);
+    let kept_binaries: Vec<_> = install_manifest
+        .paths()
+        .filter(|path| {
+            !args.targets.selects_everything(package_name) && path.starts_with(&binary_dir)
+        })
+        .map(Path::to_owned)
+        .collect();
+
+    let has_shared_libraries = staged
+        .paths()
+        .chain(kept_binaries.iter().map(PathBuf::as_path))
+        .any(|path| path.starts_with(&binary_dir) && is_shared_library(path));
+    let hooks = environment_hooks(package_name, has_shared_libraries);
+    install_environment_hooks(
+        staging.path(),
+        staging.install_base(),
+        package_name,
+        &hooks,
+        &mut staged,
+    )?;
+
+    // Files of removed sources, binaries and metadata entries must not linger. The previous
+    // install manifest knows about most of them, and the directories that are only ever written
+    // by this plugin are searched as well, in case there is no manifest yet.
+    let mut outdated = vec![Path::new("share").join(package_name).join("rust")];
+    if args.targets.selects_everything(package_name) {
+        outdated.push(binary_dir.clone());
+    }
+    outdated.extend(
+        install_manifest
+            .paths()
+            .filter(|path| !kept_binaries.iter().any(|kept| kept == path))
+            .map(Path::to_owned),
+    );
+    staging.commit(&staged, &outdated)?;
+
+    // Files from earlier installs that are not reinstalled stay recorded if they still exist, so
+    // that they are removed when uninstalling
+    install_manifest.extend(&staged);
+    install_manifest.write(&manifest_path)
+}
+
+/// Installs all files of a package to the staging directory.
+fn install_files(
+    args: &Args,
+    staging_dir: &Path,
+    install_base: &Path,
+    package: &Package,
+    metadata: &Metadata,
+    artifacts: &[Artifact],
+    install_manifest: &mut InstallManifest,
+) -> Result<()> {
     let package_name = &package.name;
-    let package_path = args
+    let package_path = package
         .manifest_path
         .parent()
         .ok_or(anyhow!("Manifest path must have a parent."))?;
     // Putting marker file creation after the actual build command means that
     // we create less garbage if the build command failed.
-    create_package_marker(&args.install_base, "packages", package_name)?;
+    create_package_marker(
+        staging_dir,
+        "packages",
+        package_name,
+        None,
+        install_manifest,
+    )?;
 
     // If this package should be included in ros-env, we do not want `colcon-ros-cargo` to
     // find the package as it should not be patched.
-    let include_ros_env = package
-        .metadata
-        .as_ref()
-        .and_then(|metadata| metadata.get("ros-env"))
-        .and_then(|ros_env| ros_env.get("include"))
-        .and_then(|include| include.as_bool())
-        .unwrap_or(false);
-
-    if !include_ros_env {
+    if !includes_ros_env(package) {
         // This marker is used by colcon-ros-cargo when looking for dependencies
-        create_package_marker(&args.install_base, "rust_packages", package_name)?;
+        create_package_marker(
+            staging_dir,
+            "rust_packages",
+            package_name,
+            None,
+            install_manifest,
+        )?;
+    }
+
+    // The install base itself is not a parent prefix, even if it is already sourced
+    let ament_prefix_path = std::env::var_os("AMENT_PREFIX_PATH").unwrap_or_default();
+    let parent_prefixes: Vec<_> = std::env::split_paths(&ament_prefix_path)
+        .filter(|prefix| {
+            !prefix.as_os_str().is_empty()
+                && prefix.canonicalize().ok() != install_base.canonicalize().ok()
+        })
+        .collect();
+    // Packages that are not ROS packages, e.g. selected without a package.xml, have no
+    // dependencies to register
+    let package_xml_path = package_path.join("package.xml");
+    if package_xml_path.exists() {
+        let package_xml = PackageXml::read(&package_xml_path)?;
+        register_dependency_resources(
+            staging_dir,
+            package_name,
+            &package_xml,
+            &parent_prefixes,
+            install_manifest,
+        )?;
     }
 
+    // Path dependencies are looked up in the install base first, then in the install bases of the
+    // other packages built together, which are installed before the packages depending on them,
+    // and finally in the underlays
+    let mut prefixes = vec![install_base.to_owned()];
+    for prefix in workspace_install_bases(args, metadata)? {
+        if !prefixes.contains(&prefix) {
+            prefixes.push(prefix);
+        }
+    }
+    prefixes.extend(parent_prefixes);
     install_package(
-        &args.install_base,
-        package_path,
-        &args.manifest_path,
-        package_name,
-        &manifest,
+        staging_dir,
+        package,
+        &metadata.workspace_root,
+        &prefixes,
+        args.install_mode,
+        install_manifest,
     )?;
+    // Cargo also reports the artifacts of dependencies, which are not installed
+    let artifacts: Vec<_> = artifacts
+        .iter()
+        .filter(|artifact| artifact.package_id == package.id)
+        .cloned()
+        .collect();
+    let features = args.features.resolve(package, &workspace_members(metadata));
+    let binaries = expected_binaries(package, &features, &args.targets);
     install_binaries(
-        &args.install_base,
-        &args.build_base,
+        staging_dir,
         package_name,
-        &args.profile,
-        args.arch.as_deref(),
-        &args.features,
-        // Unwrap is safe since complete_from_path() has been called
-        &manifest.bin,
+        &binaries,
+        &artifacts,
+        install_manifest,
     )?;
     install_files_from_metadata(
-        &args.install_base,
+        staging_dir,
         package_path,
         package_name,
-        package.metadata.as_ref(),
+        &package.metadata,
+        args.install_mode,
+        install_manifest,
     )?;
-    Ok(true)
+    Ok(())
 }
@@ -0,0 +1,424 @@
+// Licensed under the Apache License, Version 2.0
+
+use anyhow::{anyhow, Context, Result};
+use toml_edit::{Array, DocumentMut, InlineTable, Item, Table, Value};
+
+use std::path::{Path, PathBuf};
+
+use crate::{find_package, read_metadata};
+
+/// The package fields whose values are paths relative to the manifest.
+const PATH_FIELDS: [&str; 2] = ["readme", "license-file"];
+
+/// Tables containing dependencies, at the top level or below `[target.'cfg(…)']`.
+const DEPENDENCY_TABLES: [&str; 3] = ["dependencies", "dev-dependencies", "build-dependencies"];
+
+/// The parts of the workspace manifest that packages can inherit from.
+#[derive(Default)]
+struct WorkspaceInheritance {
+    root: PathBuf,
+    package: Table,
+    dependencies: Table,
+    lints: Option<Item>,
+}
+
+/// Produce a self-contained version of a package manifest, similar to `cargo package`.
+///
+/// Fields that are inherited from the workspace (`*.workspace = true`) are expanded. Path
+/// dependencies are pointed to the installed source of the dependency if it is found in one of
+/// the `prefixes`, and otherwise turned into versioned dependencies.
+pub(crate) fn normalize_manifest(
+    manifest_path: &Path,
+    workspace_root: &Path,
+    prefixes: &[PathBuf],
+) -> Result<String> {
+    let mut manifest = read_document(manifest_path)?;
+    // unwrap is ok since a manifest path always has a parent
+    let package_dir = manifest_path.parent().unwrap();
+    let workspace = read_workspace_inheritance(workspace_root)?;
+
+    let package = manifest
+        .get_mut("package")
+        .and_then(Item::as_table_like_mut)
+        .ok_or(anyhow!("Cargo manifest has no package section."))?;
+    // The workspace is not installed alongside the package
+    package.remove("workspace");
+    for (mut key, item) in package.iter_mut() {
+        if !is_inherited(item) {
+            continue;
+        }
+        let mut value = workspace.package.get(key.get()).cloned().ok_or_else(|| {
+            anyhow!("'package.{key}' is inherited, but [workspace.package] does not define it")
+        })?;
+        if PATH_FIELDS.contains(&key.get()) {
+            if let Some(path) = value.as_str() {
+                value = toml_edit::value(absolute(&workspace.root, path));
+            }
+        }
+        // Drop the formatting of the workspace manifest and of dotted keys like `version.workspace`
+        if let Some(value) = value.as_value_mut() {
+            value.decor_mut().clear();
+        }
+        *item = value;
+        key.fmt();
+    }
+
+    if manifest.get("lints").is_some_and(is_inherited) {
+        let lints = workspace.lints.clone().ok_or(anyhow!(
+            "'lints' are inherited, but [workspace.lints] does not exist"
+        ))?;
+        manifest.insert("lints", lints);
+    }
+    manifest.remove("workspace");
+
+    // Collect the dependency tables along with whether they contain dev-dependencies
+    let mut dependency_tables = Vec::new();
+    for (key, item) in manifest.iter_mut() {
+        if DEPENDENCY_TABLES.contains(&key.get()) {
+            dependency_tables.push((key.get() == "dev-dependencies", item));
+        } else if key.get() == "target" {
+            let targets = item
+                .as_table_like_mut()
+                .ok_or(anyhow!("'target' is not a table"))?;
+            for (_, target) in targets.iter_mut() {
+                let Some(target) = target.as_table_like_mut() else {
+                    continue;
+                };
+                for (key, item) in target.iter_mut() {
+                    if DEPENDENCY_TABLES.contains(&key.get()) {
+                        dependency_tables.push((key.get() == "dev-dependencies", item));
+                    }
+                }
+            }
+        }
+    }
+    for (dev, item) in dependency_tables {
+        normalize_dependencies(item, dev, package_dir, &workspace, prefixes)?;
+    }
+
+    Ok(format!(
+        "# This file was generated by cargo-ament-build from '{}'.\n\n{manifest}",
+        manifest_path.display()
+    ))
+}
+
+/// Expand inherited dependencies and replace paths in one dependency table.
+fn normalize_dependencies(
+    item: &mut Item,
+    dev: bool,
+    package_dir: &Path,
+    workspace: &WorkspaceInheritance,
+    prefixes: &[PathBuf],
+) -> Result<()> {
+    // Dependencies declared as `[dependencies.foo]` leave an implicit parent table behind, which
+    // would not be written out once it contains the dependencies as values.
+    if let Item::Table(table) = item {
+        table.set_implicit(false);
+    }
+    let table = item
+        .as_table_like_mut()
+        .ok_or(anyhow!("Dependencies must be a table"))?;
+    let names: Vec<String> = table.iter().map(|(name, _)| name.to_owned()).collect();
+    for name in names {
+        // unwrap is ok since the name comes from the table itself
+        let item = table.get(&name).unwrap();
+        let dependency = normalize_dependency(&name, item, dev, package_dir, workspace, prefixes)
+            .with_context(|| format!("Could not normalize dependency '{name}'"))?;
+        match dependency {
+            Some(mut dependency) => {
+                dependency.decor_mut().clear();
+                table.insert(&name, toml_edit::value(dependency));
+                if let Some(mut key) = table.key_mut(&name) {
+                    key.fmt();
+                }
+            }
+            None => {
+                table.remove(&name);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the normalized dependency, or None if it should be dropped.
+fn normalize_dependency(
+    name: &str,
+    item: &Item,
+    dev: bool,
+    package_dir: &Path,
+    workspace: &WorkspaceInheritance,
+    prefixes: &[PathBuf],
+) -> Result<Option<InlineTable>> {
+    let mut dependency =
+        to_inline_table(item).ok_or(anyhow!("Dependency is neither a version nor a table"))?;
+    // Paths are relative to the manifest that declares them
+    let mut base_dir = package_dir;
+    if dependency.get("workspace").and_then(Value::as_bool) == Some(true) {
+        let mut inherited = workspace
+            .dependencies
+            .get(name)
+            .and_then(to_inline_table)
+            .ok_or(anyhow!(
+                "The dependency is inherited, but [workspace.dependencies] does not define it"
+            ))?;
+        for (key, value) in dependency.iter() {
+            match key {
+                "workspace" => {}
+                // Features are additive, everything else is overridden by the package
+                "features" => {
+                    let features = inherited
+                        .entry("features")
+                        .or_insert(Value::Array(Array::new()));
+                    if let (Value::Array(features), Value::Array(additional)) = (features, value) {
+                        features.extend(additional.iter().cloned());
+                    }
+                }
+                _ => {
+                    inherited.insert(key, value.clone());
+                }
+            }
+        }
+        dependency = inherited;
+        base_dir = &workspace.root;
+    }
+
+    let Some(path) = dependency.get("path").and_then(Value::as_str) else {
+        return Ok(Some(dependency));
+    };
+    let path = base_dir.join(path);
+    let package_name = dependency
+        .get("package")
+        .and_then(Value::as_str)
+        .unwrap_or(name)
+        .to_owned();
+    if let Some(installed) = find_installed_source(&package_name, prefixes) {
+        dependency.insert("path", installed.display().to_string().into());
+    } else if !dependency.contains_key("version") && dev {
+        // Like `cargo package`, drop dev-dependencies that are only available locally
+        return Ok(None);
+    } else {
+        dependency.remove("path");
+        if !dependency.contains_key("version") {
+            let manifest_path = path
+                .join("Cargo.toml")
+                .canonicalize()
+                .with_context(|| format!("Path dependency '{}' does not exist", path.display()))?;
+            let metadata = read_metadata(&manifest_path)?;
+            let version = find_package(&metadata, &manifest_path)?.version.to_string();
+            dependency.insert("version", version.into());
+        }
+    }
+    Ok(Some(dependency))
+}
+
+/// Find the installed source code of a package, as placed by `install_package`.
+fn find_installed_source(package_name: &str, prefixes: &[PathBuf]) -> Option<PathBuf> {
+    prefixes
+        .iter()
+        .map(|prefix| prefix.join("share").join(package_name).join("rust"))
+        .find(|dir| dir.join("Cargo.toml").is_file())
+}
+
+fn read_workspace_inheritance(workspace_root: &Path) -> Result<WorkspaceInheritance> {
+    let manifest_path = workspace_root.join("Cargo.toml");
+    let mut inheritance = WorkspaceInheritance {
+        root: workspace_root.to_owned(),
+        ..Default::default()
+    };
+    let Some(workspace) = read_document(&manifest_path)?
+        .remove("workspace")
+        .and_then(|item| item.into_table().ok())
+    else {
+        // A package that is not part of a workspace is its own workspace root
+        return Ok(inheritance);
+    };
+    for (key, item) in workspace {
+        match (key.as_str(), item) {
+            ("package", Item::Table(table)) => inheritance.package = table,
+            ("dependencies", Item::Table(table)) => inheritance.dependencies = table,
+            ("lints", item) => inheritance.lints = Some(item),
+            _ => {}
+        }
+    }
+    Ok(inheritance)
+}
+
+fn read_document(manifest_path: &Path) -> Result<DocumentMut> {
+    std::fs::read_to_string(manifest_path)
+        .with_context(|| format!("Failed to read '{}'", manifest_path.display()))?
+        .parse()
+        .with_context(|| format!("Failed to parse '{}'", manifest_path.display()))
+}
+
+/// Whether an item is `{ workspace = true }`, in any of its spellings.
+fn is_inherited(item: &Item) -> bool {
+    item.as_table_like()
+        .and_then(|table| table.get("workspace"))
+        .and_then(Item::as_bool)
+        == Some(true)
+}
+
+fn to_inline_table(item: &Item) -> Option<InlineTable> {
+    match item {
+        Item::Value(Value::String(version)) => {
+            let mut table = InlineTable::new();
+            table.insert("version", Value::from(version.value().as_str()));
+            Some(table)
+        }
+        Item::Value(Value::InlineTable(table)) => Some(table.clone()),
+        Item::Table(table) => Some(table.clone().into_inline_table()),
+        _ => None,
+    }
+}
+
+fn absolute(base_dir: &Path, path: &str) -> String {
+    base_dir.join(path).display().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn write(path: impl AsRef<Path>, contents: &str) -> Result<()> {
+        let path = path.as_ref();
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, contents)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_manifest_in_workspace() -> Result<()> {
+        let tmp = tempdir()?;
+        let workspace = tmp.path().join("ws");
+        let install_base = tmp.path().join("install");
+        write(
+            workspace.join("Cargo.toml"),
+            r#"[workspace]
+members = ["node", "util", "installed", "local"]
+
+[workspace.package]
+version = "1.2.3"
+edition = "2021"
+readme = "README.md"
+
+[workspace.dependencies]
+anyhow = { version = "1", features = ["std"] }
+util = { path = "util", version = "1.2.3" }
+
+[workspace.lints.rust]
+unsafe_code = "forbid"
+"#,
+        )?;
+        write(
+            workspace.join("node/Cargo.toml"),
+            r#"[package]
+name = "node"
+version.workspace = true
+edition.workspace = true
+readme.workspace = true
+
+[dependencies]
+anyhow = { workspace = true, features = ["backtrace"], optional = true }
+util.workspace = true
+installed = { path = "../installed" }
+
+[dependencies.local]
+path = "../local"
+
+[dev-dependencies]
+local = { path = "../local" }
+
+[target.'cfg(unix)'.build-dependencies]
+util = { path = "../util", version = "1" }
+
+[lints]
+workspace = true
+"#,
+        )?;
+        for (member, version) in [
+            ("util", "1.2.3"),
+            ("installed", "0.1.0"),
+            ("local", "0.4.0"),
+        ] {
+            write(
+                workspace.join(member).join("Cargo.toml"),
+                &format!("[package]\nname = \"{member}\"\nversion = \"{version}\"\n"),
+            )?;
+            write(workspace.join(member).join("src/lib.rs"), "")?;
+        }
+        write(workspace.join("node/src/main.rs"), "")?;
+        write(install_base.join("share/installed/rust/Cargo.toml"), "")?;
+
+        let normalized = normalize_manifest(
+            &workspace.join("node/Cargo.toml").canonicalize()?,
+            &workspace.canonicalize()?,
+            std::slice::from_ref(&install_base),
+        )?;
+        let manifest: DocumentMut = normalized.parse()?;
+
+        let package = &manifest["package"];
+        assert_eq!(package["version"].as_str(), Some("1.2.3"));
+        assert_eq!(package["edition"].as_str(), Some("2021"));
+        assert_eq!(
+            package["readme"].as_str().map(PathBuf::from),
+            Some(workspace.canonicalize()?.join("README.md"))
+        );
+        assert_eq!(
+            manifest["lints"]["rust"]["unsafe_code"].as_str(),
+            Some("forbid")
+        );
+        assert!(manifest.get("workspace").is_none());
+
+        let dependencies = &manifest["dependencies"];
+        let anyhow = dependencies["anyhow"].as_inline_table().unwrap();
+        assert_eq!(anyhow.get("version").and_then(Value::as_str), Some("1"));
+        assert_eq!(anyhow.get("optional").and_then(Value::as_bool), Some(true));
+        let features: Vec<_> = anyhow
+            .get("features")
+            .and_then(Value::as_array)
+            .unwrap()
+            .iter()
+            .filter_map(Value::as_str)
+            .collect();
+        assert_eq!(features, ["std", "backtrace"]);
+        // Not installed, but versioned
+        let util = dependencies["util"].as_inline_table().unwrap();
+        assert_eq!(util.get("version").and_then(Value::as_str), Some("1.2.3"));
+        assert!(util.get("path").is_none());
+        // Installed in one of the prefixes
+        let installed = dependencies["installed"].as_inline_table().unwrap();
+        assert_eq!(
+            installed
+                .get("path")
+                .and_then(Value::as_str)
+                .map(PathBuf::from),
+            Some(install_base.join("share/installed/rust"))
+        );
+        // Neither installed nor versioned, so the version is looked up
+        let local = dependencies["local"].as_inline_table().unwrap();
+        assert_eq!(local.get("version").and_then(Value::as_str), Some("0.4.0"));
+        assert!(local.get("path").is_none());
+        // Local dev-dependencies are dropped
+        assert!(manifest["dev-dependencies"].get("local").is_none());
+        let build_util = manifest["target"]["cfg(unix)"]["build-dependencies"]["util"]
+            .as_inline_table()
+            .unwrap();
+        assert_eq!(build_util.get("version").and_then(Value::as_str), Some("1"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_manifest_without_workspace() -> Result<()> {
+        let tmp = tempdir()?;
+        let package_dir = tmp.path().join("pkg");
+        let manifest =
+            "[package]\nname = \"pkg\"\nversion = \"0.1.0\"\n\n[dependencies]\nanyhow = \"1\"\n";
+        write(package_dir.join("Cargo.toml"), manifest)?;
+
+        let normalized = normalize_manifest(&package_dir.join("Cargo.toml"), &package_dir, &[])?;
+
+        assert!(normalized.ends_with("[dependencies]\nanyhow = { version = \"1\" }\n"));
+        Ok(())
+    }
+}
@@ -0,0 +1,580 @@
+// Licensed under the Apache License, Version 2.0
+
+use anyhow::{anyhow, bail, Context, Result};
+use cargo_metadata::{DependencyKind, Package};
+
+use std::path::Path;
+
+/// The kinds of dependencies that a `package.xml` distinguishes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DependencyType {
+    Build,
+    BuildExport,
+    Buildtool,
+    BuildtoolExport,
+    Exec,
+    Test,
+    Doc,
+}
+
+/// A dependency declared in a `package.xml`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Dependency {
+    pub name: String,
+    pub dependency_type: DependencyType,
+}
+
+/// The parts of a `package.xml` that are relevant for building and installing a package
+///
+/// Dependencies whose `condition` attribute evaluates to false are left out. Like in catkin_pkg,
+/// `<depend>` stands for a build, build export and exec dependency, and the format 1
+/// `<run_depend>` for a build export and exec dependency.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PackageXml {
+    pub format: u32,
+    pub name: String,
+    pub version: String,
+    pub dependencies: Vec<Dependency>,
+    /// The `<build_type>` in the `<export>` section
+    pub build_type: Option<String>,
+}
+
+impl PackageXml {
+    /// Reads and parses a `package.xml` file.
+    pub fn read(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read '{}'", path.display()))?;
+        Self::parse(&content).with_context(|| format!("Failed to parse '{}'", path.display()))
+    }
+
+    /// Parses the contents of a `package.xml` file, evaluating conditions with the environment
+    /// variables of this process.
+    pub fn parse(content: &str) -> Result<Self> {
+        let document = roxmltree::Document::parse(content)?;
+        let root = document.root_element();
+        if !root.has_tag_name("package") {
+            bail!("The root element must be <package>");
+        }
+        let format = match root.attribute("format") {
+            Some(format) => format
+                .parse()
+                .map_err(|_| anyhow!("Invalid package format '{format}'"))?,
+            None => 1,
+        };
+        let text = |tag: &str| -> Result<String> {
+            root.children()
+                .find(|node| node.has_tag_name(tag))
+                .and_then(|node| node.text())
+                .map(|text| text.trim().to_owned())
+                .ok_or_else(|| anyhow!("The <{tag}> element is missing"))
+        };
+        let mut package_xml = Self {
+            format,
+            name: text("name")?,
+            version: text("version")?,
+            dependencies: Vec::new(),
+            build_type: None,
+        };
+        let exports = root
+            .children()
+            .filter(|node| node.has_tag_name("export"))
+            .flat_map(|node| node.children());
+        for node in exports.filter(|node| node.has_tag_name("build_type")) {
+            if let Some(condition) = node.attribute("condition") {
+                if !evaluate_condition(condition, |name| std::env::var(name).ok())? {
+                    continue;
+                }
+            }
+            package_xml.build_type = node.text().map(|text| text.trim().to_owned());
+            break;
+        }
+        for node in root.children().filter(roxmltree::Node::is_element) {
+            let Some(dependency_types) = dependency_types(node.tag_name().name()) else {
+                continue;
+            };
+            if let Some(condition) = node.attribute("condition") {
+                if !evaluate_condition(condition, |name| std::env::var(name).ok())? {
+                    continue;
+                }
+            }
+            let name = node
+                .text()
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .ok_or_else(|| anyhow!("The <{}> element is empty", node.tag_name().name()))?;
+            package_xml
+                .dependencies
+                .extend(dependency_types.iter().map(|&dependency_type| Dependency {
+                    name: name.to_owned(),
+                    dependency_type,
+                }));
+        }
+        Ok(package_xml)
+    }
+
+    /// The names of the dependencies of the given type, in the order they are declared.
+    pub fn dependencies(&self, dependency_type: DependencyType) -> impl Iterator<Item = &str> {
+        self.dependencies
+            .iter()
+            .filter(move |dependency| dependency.dependency_type == dependency_type)
+            .map(|dependency| dependency.name.as_str())
+    }
+
+    /// The dependencies needed when using the installed package, without duplicates.
+    ///
+    /// Like in ament_cmake, these are the build export, buildtool export and exec dependencies.
+    pub fn run_dependencies(&self) -> Vec<&str> {
+        let mut run_dependencies = Vec::new();
+        for dependency_type in [
+            DependencyType::BuildExport,
+            DependencyType::BuildtoolExport,
+            DependencyType::Exec,
+        ] {
+            for name in self.dependencies(dependency_type) {
+                if !run_dependencies.contains(&name) {
+                    run_dependencies.push(name);
+                }
+            }
+        }
+        run_dependencies
+    }
+
+    /// Checks that the `package.xml` describes the same package as the `Cargo.toml`.
+    ///
+    /// The `<name>` must equal the Cargo package name, unless a different one is configured:
+    /// ```toml
+    /// [package.metadata.ros]
+    /// package_name = "my_package"
+    /// ```
+    /// The `<version>` must equal the Cargo version without pre-release and build metadata, which
+    /// `package.xml` cannot express, and the build type must be `ament_cargo`. Mismatches are
+    /// returned as warnings together with those for things that still work, like the deprecated
+    /// format 1. With `strict`, all mismatches are reported in a single error instead.
+    pub fn validate(&self, package: &Package, strict: bool) -> Result<Vec<String>> {
+        let mut warnings = Vec::new();
+        let mut errors = Vec::new();
+        let mut mismatches = Vec::new();
+        match self.format {
+            1 => warnings.push(
+                "Package format 1 is deprecated, consider migrating to format 3 (REP 149)".into(),
+            ),
+            2 | 3 => {}
+            format => errors.push(format!(
+                "Package format {format} is not supported, only formats 1 to 3 are"
+            )),
+        }
+        let expected_name = match package
+            .metadata
+            .get("ros")
+            .and_then(|ros| ros.get("package_name"))
+        {
+            Some(name) => name
+                .as_str()
+                .ok_or_else(|| anyhow!("[package.metadata.ros] package_name must be a string"))?,
+            None => package.name.as_str(),
+        };
+        if self.name != expected_name {
+            mismatches.push(format!(
+                "The name '{}' does not match the package name '{expected_name}'",
+                self.name
+            ));
+        }
+        let version = &package.version;
+        let expected_version = format!("{}.{}.{}", version.major, version.minor, version.patch);
+        if self.version != expected_version {
+            mismatches.push(format!(
+                "The version '{}' does not match the Cargo version '{version}'",
+                self.version
+            ));
+        }
+        match &self.build_type {
+            Some(build_type) if build_type == "ament_cargo" => {}
+            Some(build_type) => mismatches.push(format!(
+                "The build type '{build_type}' must be 'ament_cargo' for colcon to build the package with cargo"
+            )),
+            None => mismatches.push(
+                "The build type is missing, add <build_type>ament_cargo</build_type> to the <export> section"
+                    .into(),
+            ),
+        }
+        if strict {
+            errors.append(&mut mismatches);
+        }
+        if !errors.is_empty() {
+            bail!("{}", errors.join("\n"));
+        }
+        warnings.append(&mut mismatches);
+        Ok(warnings)
+    }
+
+    /// Compares the dependencies in the `package.xml` with the Cargo dependencies.
+    ///
+    /// Only dependencies for which `is_rust_package` is true are checked, since for other
+    /// packages, e.g. C libraries or build tools, there is no corresponding Cargo dependency.
+    /// Such Cargo dependencies must be build dependencies, or test dependencies for
+    /// dev-dependencies, so that colcon builds them first. Conversely, Rust packages in the
+    /// `package.xml` that no Cargo dependency uses are reported as well.
+    pub fn lint_dependencies(
+        &self,
+        package: &Package,
+        is_rust_package: impl Fn(&str) -> bool,
+    ) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut reported = Vec::new();
+        for dependency in &package.dependencies {
+            let name = dependency.name.as_str();
+            if !is_rust_package(name) || reported.contains(&name) {
+                continue;
+            }
+            let declared: Vec<_> = self
+                .dependencies
+                .iter()
+                .filter(|declared| declared.name == name)
+                .map(|declared| declared.dependency_type)
+                .collect();
+            if let Some(tag) = missing_dependency_tag(&dependency.kind, &declared) {
+                problems.push(format!(
+                    "The Cargo dependency '{name}' is a ROS package, but not declared with <{tag}>"
+                ));
+                reported.push(name);
+            }
+        }
+        for dependency in &self.dependencies {
+            let name = dependency.name.as_str();
+            if !is_rust_package(name) || reported.contains(&name) {
+                continue;
+            }
+            if !package.dependencies.iter().any(|dep| dep.name == name) {
+                problems.push(format!(
+                    "The dependency '{name}' in the package.xml is not used by any Cargo dependency"
+                ));
+                reported.push(name);
+            }
+        }
+        problems
+    }
+}
+
+/// The element that is missing for a Cargo dependency of the given kind, if the `package.xml`
+/// declares the package with the `declared` dependency types, or None if nothing is missing.
+///
+/// Cargo dependencies must be build dependencies, or test dependencies for dev-dependencies.
+/// A package that is an exec or build export dependency already needs a `<build_depend>`,
+/// since a `<depend>` would declare those a second time, which is invalid.
+pub(crate) fn missing_dependency_tag(
+    kind: &DependencyKind,
+    declared: &[DependencyType],
+) -> Option<&'static str> {
+    use DependencyType::*;
+    let (wanted, tag): (&[_], _) = match kind {
+        DependencyKind::Development => (&[Build, Test], "test_depend"),
+        _ if declared.contains(&Exec) || declared.contains(&BuildExport) => {
+            (&[Build], "build_depend")
+        }
+        _ => (&[Build], "depend"),
+    };
+    if declared.iter().any(|t| wanted.contains(t)) {
+        None
+    } else {
+        Some(tag)
+    }
+}
+
+/// The dependency types that a dependency element stands for, or None for other elements.
+pub(crate) fn dependency_types(tag: &str) -> Option<&'static [DependencyType]> {
+    use DependencyType::*;
+    Some(match tag {
+        "build_depend" => &[Build],
+        "build_export_depend" => &[BuildExport],
+        "buildtool_depend" => &[Buildtool],
+        "buildtool_export_depend" => &[BuildtoolExport],
+        "exec_depend" => &[Exec],
+        "test_depend" => &[Test],
+        "doc_depend" => &[Doc],
+        "depend" => &[Build, BuildExport, Exec],
+        "run_depend" => &[BuildExport, Exec],
+        _ => return None,
+    })
+}
+
+/// Evaluates a dependency condition such as `$ROS_VERSION == 2`, as specified in REP 149.
+///
+/// Comparisons with `==`, `!=`, `<`, `<=`, `>` and `>=` can be grouped with parentheses and
+/// combined with `and` and `or`, where `and` binds more strongly. Like in catkin_pkg, values are
+/// compared as strings and unset variables are empty.
+fn evaluate_condition(condition: &str, var: impl Fn(&str) -> Option<String>) -> Result<bool> {
+    let mut tokens = tokenize(condition, var)
+        .ok_or_else(|| anyhow!("Invalid condition '{condition}'"))?
+        .into_iter()
+        .peekable();
+    match parse_or(&mut tokens) {
+        Some(value) if tokens.peek().is_none() => Ok(value),
+        _ => bail!("Invalid condition '{condition}'"),
+    }
+}
+
+/// A token of a dependency condition
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    LeftParen,
+    RightParen,
+    And,
+    Or,
+    Operator(&'static str),
+    /// A literal, or the value of a variable
+    Value(String),
+}
+
+type Tokens = std::iter::Peekable<std::vec::IntoIter<Token>>;
+
+/// Splits a condition into tokens, replacing variables with their values.
+///
+/// Operators need not be surrounded by spaces, and literals can be quoted.
+fn tokenize(condition: &str, var: impl Fn(&str) -> Option<String>) -> Option<Vec<Token>> {
+    let is_word_char = |c: char| c.is_alphanumeric() || matches!(c, '_' | '-' | '.');
+    let mut tokens = Vec::new();
+    let mut rest = condition.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = match c {
+            '(' => {
+                tokens.push(Token::LeftParen);
+                1
+            }
+            ')' => {
+                tokens.push(Token::RightParen);
+                1
+            }
+            '=' | '!' | '<' | '>' => {
+                let operator = ["==", "!=", "<=", ">=", "<", ">"]
+                    .into_iter()
+                    .find(|operator| rest.starts_with(operator))?;
+                tokens.push(Token::Operator(operator));
+                operator.len()
+            }
+            '"' | '\'' => {
+                let end = rest[1..].find(c)? + 1;
+                tokens.push(Token::Value(rest[1..end].to_owned()));
+                end + 1
+            }
+            _ if c == '$' || is_word_char(c) => {
+                let len = rest[1..]
+                    .find(|c: char| !is_word_char(c))
+                    .map_or(rest.len(), |len| len + 1);
+                tokens.push(match &rest[..len] {
+                    "$" => return None,
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    word => match word.strip_prefix('$') {
+                        Some(name) => Token::Value(var(name).unwrap_or_default()),
+                        None => Token::Value(word.to_owned()),
+                    },
+                });
+                len
+            }
+            _ => return None,
+        };
+        rest = rest[len..].trim_start();
+    }
+    Some(tokens)
+}
+
+/// Parses alternatives separated by `or`.
+fn parse_or(tokens: &mut Tokens) -> Option<bool> {
+    let mut value = parse_and(tokens)?;
+    while tokens.next_if_eq(&Token::Or).is_some() {
+        // Both sides are parsed, so that invalid conditions are always rejected
+        value |= parse_and(tokens)?;
+    }
+    Some(value)
+}
+
+/// Parses terms separated by `and`.
+fn parse_and(tokens: &mut Tokens) -> Option<bool> {
+    let mut value = parse_term(tokens)?;
+    while tokens.next_if_eq(&Token::And).is_some() {
+        value &= parse_term(tokens)?;
+    }
+    Some(value)
+}
+
+/// Parses a comparison, or a condition in parentheses.
+fn parse_term(tokens: &mut Tokens) -> Option<bool> {
+    match tokens.next()? {
+        Token::LeftParen => {
+            let value = parse_or(tokens)?;
+            tokens.next_if_eq(&Token::RightParen)?;
+            Some(value)
+        }
+        Token::Value(lhs) => {
+            let (Token::Operator(operator), Token::Value(rhs)) = (tokens.next()?, tokens.next()?)
+            else {
+                return None;
+            };
+            let ordering = lhs.cmp(&rhs);
+            Some(match operator {
+                "==" => ordering.is_eq(),
+                "!=" => ordering.is_ne(),
+                "<" => ordering.is_lt(),
+                "<=" => ordering.is_le(),
+                ">" => ordering.is_gt(),
+                ">=" => ordering.is_ge(),
+                _ => unreachable!("Unknown operator '{operator}'"),
+            })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{dependency, package};
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let package_xml = PackageXml::parse(
+            r#"<?xml version="1.0"?>
+            <package format="3">
+              <name>my_package</name>
+              <version>0.1.0</version>
+              <buildtool_depend>ament_cargo</buildtool_depend>
+              <depend>rclrs</depend>
+              <exec_depend>std_msgs</exec_depend>
+              <exec_depend condition="$ROS_VERSION == 1">roscpp</exec_depend>
+              <build_export_depend>std_msgs</build_export_depend>
+              <test_depend>ament_lint_auto</test_depend>
+            </package>"#,
+        )?;
+        assert_eq!(package_xml.format, 3);
+        assert_eq!(package_xml.name, "my_package");
+        assert_eq!(package_xml.version, "0.1.0");
+        assert_eq!(
+            package_xml
+                .dependencies(DependencyType::Build)
+                .collect::<Vec<_>>(),
+            ["rclrs"]
+        );
+        assert_eq!(package_xml.run_dependencies(), ["rclrs", "std_msgs"]);
+        assert!(PackageXml::parse("<package><name>a</name></package>").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate() -> Result<()> {
+        let mut package = package(serde_json::json!({ "version": "0.2.0-alpha.1" }));
+        let package_xml = |format: u32, name: &str, version: &str, build_type: &str| {
+            PackageXml::parse(&format!(
+                r#"<package format="{format}">
+                  <name>{name}</name>
+                  <version>{version}</version>
+                  <export><build_type>{build_type}</build_type></export>
+                </package>"#
+            ))
+        };
+
+        let valid = package_xml(3, "my_package", "0.2.0", "ament_cargo")?;
+        assert_eq!(valid.build_type.as_deref(), Some("ament_cargo"));
+        assert!(valid.validate(&package, true)?.is_empty());
+        assert_eq!(
+            package_xml(1, "my_package", "0.2.0", "ament_cargo")?
+                .validate(&package, true)?
+                .len(),
+            1
+        );
+        let mismatched = package_xml(3, "other", "0.1.0", "ament_cmake")?;
+        // Mismatches are only warnings, unless the validation is strict
+        assert_eq!(mismatched.validate(&package, false)?.len(), 3);
+        let error = mismatched.validate(&package, true).unwrap_err().to_string();
+        assert_eq!(error.lines().count(), 3, "{error}");
+        assert!(package_xml(4, "my_package", "0.2.0", "ament_cargo")?
+            .validate(&package, false)
+            .is_err());
+
+        package.metadata = serde_json::json!({ "ros": { "package_name": "other" } });
+        assert!(package_xml(2, "other", "0.2.0", "ament_cargo")?
+            .validate(&package, true)
+            .is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lint_dependencies() -> Result<()> {
+        let package = package(serde_json::json!({
+            "dependencies": [
+                dependency("rclrs", None),
+                dependency("std_msgs", None),
+                dependency("test_msgs", Some("dev")),
+                dependency("anyhow", None),
+            ],
+        }));
+        let package_xml = PackageXml::parse(
+            r#"<package format="3">
+              <name>my_package</name>
+              <version>0.1.0</version>
+              <buildtool_depend>ament_cargo</buildtool_depend>
+              <depend>rclrs</depend>
+              <exec_depend>std_msgs</exec_depend>
+              <test_depend>test_msgs</test_depend>
+              <depend>example_interfaces</depend>
+            </package>"#,
+        )?;
+        let rust_packages = ["rclrs", "std_msgs", "test_msgs", "example_interfaces"];
+
+        let problems =
+            package_xml.lint_dependencies(&package, |name| rust_packages.contains(&name));
+        assert_eq!(problems.len(), 2, "{problems:?}");
+        assert!(problems[0].contains("'std_msgs'"));
+        // A <depend> would duplicate the <exec_depend>
+        assert!(problems[0].contains("<build_depend>"), "{problems:?}");
+        assert!(problems[1].contains("'example_interfaces'"));
+        assert!(package_xml
+            .lint_dependencies(&package, |_| false)
+            .is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_evaluate_condition() -> Result<()> {
+        let var = |name: &str| (name == "ROS_VERSION").then(|| "2".to_owned());
+        assert!(evaluate_condition("$ROS_VERSION == 2", var)?);
+        assert!(!evaluate_condition("$ROS_VERSION != 2", var)?);
+        assert!(evaluate_condition(
+            "$ROS_VERSION == 1 or $UNSET == ''",
+            var
+        )?);
+        assert!(!evaluate_condition(
+            "$ROS_VERSION == 2 and $ROS_DISTRO == humble",
+            var
+        )?);
+
+        // Grouped conditions, comparisons and operators without spaces
+        let var = |name: &str| match name {
+            "ROS_VERSION" => Some("2".to_owned()),
+            "ROS_DISTRO" => Some("humble".to_owned()),
+            _ => None,
+        };
+        assert!(evaluate_condition(
+            "($ROS_VERSION == 1 or $ROS_VERSION == 2) and $ROS_DISTRO != jazzy",
+            var
+        )?);
+        assert!(!evaluate_condition(
+            "$ROS_VERSION == 1 or ($ROS_VERSION == 2 and $ROS_DISTRO == jazzy)",
+            var
+        )?);
+        assert!(evaluate_condition("$ROS_VERSION>=2", var)?);
+        assert!(evaluate_condition(
+            "$ROS_DISTRO < \"jazzy\" and $ROS_DISTRO > foxy",
+            var
+        )?);
+        assert!(!evaluate_condition("$ROS_VERSION<=1", var)?);
+        assert!(evaluate_condition("(($ROS_VERSION == 2))", var)?);
+
+        assert!(evaluate_condition("($ROS_VERSION == 2", var).is_err());
+        assert!(evaluate_condition("$ROS_VERSION == 2)", var).is_err());
+        assert!(evaluate_condition("$ROS_VERSION = 2", var).is_err());
+        assert!(evaluate_condition("$ROS_VERSION == 2 or", var).is_err());
+        assert!(evaluate_condition("$ROS_VERSION", var).is_err());
+        Ok(())
+    }
+}
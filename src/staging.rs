@@ -0,0 +1,321 @@
+// Licensed under the Apache License, Version 2.0
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::InstallManifest;
+
+use std::path::{Path, PathBuf};
+
+/// A temporary install space next to the install base, which is moved into place once all
+/// files of a package have been installed to it
+///
+/// If the installation fails before [`StagedInstall::commit`], the install base is untouched and
+/// the staging directory is removed when this is dropped.
+#[derive(Debug)]
+pub struct StagedInstall {
+    install_base: PathBuf,
+    staging_dir: PathBuf,
+    backup_dir: PathBuf,
+}
+
+impl StagedInstall {
+    /// Creates an empty staging directory for a package, removing leftovers of earlier runs.
+    ///
+    /// The staging directory is on the same file system as the install base, so that files can
+    /// be moved into place by renaming them.
+    pub fn new(install_base: impl AsRef<Path>, package_name: &str) -> Result<Self> {
+        let install_base = install_base.as_ref();
+        std::fs::create_dir_all(install_base).with_context(|| {
+            format!("Failed to create install base '{}'", install_base.display())
+        })?;
+        let install_base = install_base.canonicalize()?;
+        let base_name = install_base
+            .file_name()
+            .ok_or_else(|| anyhow!("The install base must not be the root directory"))?
+            .to_string_lossy();
+        let staging_dir =
+            install_base.with_file_name(format!(".{base_name}.{package_name}.staging"));
+        let backup_dir = staging_dir.with_extension("backup");
+        for dir in [&staging_dir, &backup_dir] {
+            if dir.exists() {
+                std::fs::remove_dir_all(dir).with_context(|| {
+                    format!("Failed to remove leftover directory '{}'", dir.display())
+                })?;
+            }
+        }
+        std::fs::create_dir(&staging_dir).with_context(|| {
+            format!(
+                "Failed to create staging directory '{}'",
+                staging_dir.display()
+            )
+        })?;
+        Ok(Self {
+            install_base,
+            staging_dir,
+            backup_dir,
+        })
+    }
+
+    /// The canonical path of the install base.
+    pub fn install_base(&self) -> &Path {
+        &self.install_base
+    }
+
+    /// The directory to install the package to.
+    pub fn path(&self) -> &Path {
+        &self.staging_dir
+    }
+
+    /// Creates an empty manifest for recording the files installed to the staging directory.
+    pub fn manifest(&self, compare_contents: bool) -> InstallManifest {
+        InstallManifest::staged(&self.staging_dir, &self.install_base, compare_contents)
+    }
+
+    /// Moves the files recorded in `staged` into the install base.
+    ///
+    /// Files recorded as unchanged are left as they are. The files in `outdated`, or below it for
+    /// directories, that were not installed again are removed, which is how stale content is
+    /// cleaned up. Its paths are relative to the install base. Everything that gets removed or
+    /// overwritten is kept in a backup directory, so that the previous install is restored if
+    /// moving fails.
+    pub fn commit(self, staged: &InstallManifest, outdated: &[PathBuf]) -> Result<()> {
+        let mut transaction = Transaction {
+            install_base: &self.install_base,
+            backup_dir: &self.backup_dir,
+            moved_aside: Vec::new(),
+            moved_in: Vec::new(),
+        };
+        let result = transaction.run(&self.staging_dir, staged, outdated);
+        if result.is_err() {
+            transaction.roll_back();
+        } else {
+            transaction.prune_empty_dirs();
+        }
+        result.context(
+            "Failed to move the staged install into place, the previous install was restored",
+        )
+    }
+}
+
+impl Drop for StagedInstall {
+    fn drop(&mut self) {
+        // Errors are ignored, since leftovers are removed on the next run as well
+        for dir in [&self.staging_dir, &self.backup_dir] {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+}
+
+/// The moves done while committing a staged install, for rolling them back.
+struct Transaction<'a> {
+    install_base: &'a Path,
+    backup_dir: &'a Path,
+    /// Paths in the install base and where they were moved to in the backup directory
+    moved_aside: Vec<(PathBuf, PathBuf)>,
+    /// Paths in the install base that were moved there from the staging directory
+    moved_in: Vec<PathBuf>,
+}
+
+impl Transaction<'_> {
+    fn run(
+        &mut self,
+        staging_dir: &Path,
+        staged: &InstallManifest,
+        outdated: &[PathBuf],
+    ) -> Result<()> {
+        let mut stale = Vec::new();
+        for path in outdated {
+            self.find_stale(path, staged, &mut stale)?;
+        }
+        for relative in stale {
+            self.move_aside(&relative)?;
+        }
+        for relative in staged.paths() {
+            if staged.is_unchanged(relative) {
+                continue;
+            }
+            // Symlinks from an earlier symlink install must not be written through, since that
+            // would modify the source tree
+            let mut ancestors: Vec<_> = relative
+                .ancestors()
+                .skip(1)
+                .filter(|ancestor| !ancestor.as_os_str().is_empty())
+                .collect();
+            ancestors.reverse();
+            for ancestor in ancestors {
+                if self.install_base.join(ancestor).is_symlink() {
+                    self.move_aside(ancestor)?;
+                }
+            }
+            self.move_aside(relative)?;
+            let dest = self.install_base.join(relative);
+            // unwrap is ok since the path is inside the install base
+            std::fs::create_dir_all(dest.parent().unwrap())?;
+            std::fs::rename(staging_dir.join(relative), &dest)
+                .with_context(|| format!("Failed to move '{}' into place", dest.display()))?;
+            self.moved_in.push(dest);
+        }
+        Ok(())
+    }
+
+    /// Collects the files, symlinks and empty directories at or below a path in the install base
+    /// that were not installed again.
+    fn find_stale(
+        &self,
+        relative: &Path,
+        staged: &InstallManifest,
+        stale: &mut Vec<PathBuf>,
+    ) -> Result<()> {
+        let path = self.install_base.join(relative);
+        let Ok(metadata) = path.symlink_metadata() else {
+            return Ok(());
+        };
+        if !metadata.is_dir() {
+            if !staged.contains(relative) {
+                stale.push(relative.to_owned());
+            }
+            return Ok(());
+        }
+        let mut is_empty = true;
+        for entry in std::fs::read_dir(&path)? {
+            is_empty = false;
+            self.find_stale(&relative.join(entry?.file_name()), staged, stale)?;
+        }
+        // An empty directory that is no longer installed
+        if is_empty && !staged.contains(relative) {
+            stale.push(relative.to_owned());
+        }
+        Ok(())
+    }
+
+    /// Moves a path in the install base to the backup directory, if it exists.
+    ///
+    /// Each path gets a backup of its own, numbered in the order of the moves. Mirroring the
+    /// install base would fail when a directory is moved aside after some of its files were,
+    /// e.g. when a copied directory is replaced by a symlink.
+    fn move_aside(&mut self, relative: &Path) -> Result<()> {
+        let path = self.install_base.join(relative);
+        if path.symlink_metadata().is_err() {
+            return Ok(());
+        }
+        let backup = self.backup_dir.join(self.moved_aside.len().to_string());
+        std::fs::create_dir_all(self.backup_dir)?;
+        std::fs::rename(&path, &backup)
+            .with_context(|| format!("Failed to move '{}' out of the way", path.display()))?;
+        self.moved_aside.push((path, backup));
+        Ok(())
+    }
+
+    /// Removes the directories that became empty by moving files out of them, except for empty
+    /// directories that were installed.
+    fn prune_empty_dirs(&self) {
+        for (path, _) in &self.moved_aside {
+            for dir in path.ancestors().skip(1) {
+                if dir == self.install_base
+                    || self.moved_in.iter().any(|moved_in| moved_in == dir)
+                    || std::fs::remove_dir(dir).is_err()
+                {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Undoes all moves, as far as possible.
+    fn roll_back(&mut self) {
+        for path in self.moved_in.drain(..).rev() {
+            let _ = std::fs::remove_file(&path).or_else(|_| std::fs::remove_dir(&path));
+        }
+        for (path, backup) in self.moved_aside.drain(..).rev() {
+            let _ = std::fs::rename(backup, path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_commit_replaces_directories() -> Result<()> {
+        let tmp = tempdir()?;
+        let install_base = tmp.path().join("install");
+        std::fs::create_dir_all(install_base.join("share/pkg/rust/src"))?;
+        std::fs::write(install_base.join("share/pkg/rust/src/stale.rs"), "")?;
+        std::fs::write(install_base.join("share/pkg/rust/src/unchanged.rs"), "")?;
+        std::fs::create_dir_all(install_base.join("share/pkg/rust/src/removed"))?;
+        std::fs::write(install_base.join("share/pkg/rust/src/removed/mod.rs"), "")?;
+        std::fs::create_dir_all(install_base.join("lib/pkg"))?;
+        std::fs::write(install_base.join("lib/pkg/other_node"), "old")?;
+
+        let staging = StagedInstall::new(&install_base, "pkg")?;
+        let staging_dir = staging.path().to_owned();
+        assert!(staging_dir.starts_with(tmp.path().canonicalize()?));
+        let mut staged = staging.manifest(false);
+        for file in ["share/pkg/rust/src/lib.rs", "lib/pkg/node"] {
+            let path = staging_dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::write(&path, "new")?;
+            staged.record(&path);
+        }
+        staged.record_unchanged(staging_dir.join("share/pkg/rust/src/unchanged.rs"));
+
+        staging.commit(&staged, &[PathBuf::from("share/pkg/rust")])?;
+
+        assert!(install_base.join("share/pkg/rust/src/lib.rs").is_file());
+        assert!(!install_base.join("share/pkg/rust/src/stale.rs").exists());
+        assert!(install_base
+            .join("share/pkg/rust/src/unchanged.rs")
+            .is_file());
+        assert!(!install_base.join("share/pkg/rust/src/removed").exists());
+        assert_eq!(
+            std::fs::read_to_string(install_base.join("lib/pkg/node"))?,
+            "new"
+        );
+        // Other files in directories that are not replaced are kept
+        assert!(install_base.join("lib/pkg/other_node").is_file());
+        assert!(!staging_dir.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_commit_restores_previous_install() -> Result<()> {
+        let tmp = tempdir()?;
+        let install_base = tmp.path().join("install");
+        std::fs::create_dir_all(install_base.join("share/pkg/rust"))?;
+        std::fs::write(install_base.join("share/pkg/rust/Cargo.toml"), "old")?;
+
+        let staging = StagedInstall::new(&install_base, "pkg")?;
+        let mut staged = staging.manifest(false);
+        std::fs::create_dir_all(staging.path().join("share/pkg/rust"))?;
+        std::fs::write(staging.path().join("share/pkg/rust/Cargo.toml"), "new")?;
+        staged.record(staging.path().join("share/pkg/rust/Cargo.toml"));
+        // This file was never staged, so moving it fails
+        staged.record(staging.path().join("share/pkg/package.xml"));
+
+        assert!(staging
+            .commit(&staged, &[PathBuf::from("share/pkg/rust")])
+            .is_err());
+        assert_eq!(
+            std::fs::read_to_string(install_base.join("share/pkg/rust/Cargo.toml"))?,
+            "old"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_dropped_staging_leaves_install_base_untouched() -> Result<()> {
+        let tmp = tempdir()?;
+        let install_base = tmp.path().join("install");
+
+        let staging = StagedInstall::new(&install_base, "pkg")?;
+        let staging_dir = staging.path().to_owned();
+        std::fs::write(staging_dir.join("file"), "")?;
+        drop(staging);
+
+        assert!(!staging_dir.exists());
+        assert_eq!(std::fs::read_dir(&install_base)?.count(), 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,440 @@
+// Licensed under the Apache License, Version 2.0
+
+use anyhow::{anyhow, bail, Context, Result};
+use cargo_metadata::Package;
+
+use crate::init::{package_xml, xml_escape};
+use crate::package_xml::{dependency_types, missing_dependency_tag};
+use crate::{DependencyType, InitOptions, PackageXml};
+
+use std::ops::Range;
+use std::path::Path;
+
+/// The order of the elements in a `package.xml`, as required by the schema
+const ELEMENT_ORDER: &[&str] = &[
+    "name",
+    "version",
+    "description",
+    "maintainer",
+    "license",
+    "url",
+    "author",
+    "build_depend",
+    "build_export_depend",
+    "buildtool_depend",
+    "buildtool_export_depend",
+    "exec_depend",
+    "depend",
+    "doc_depend",
+    "test_depend",
+    "conflict",
+    "replace",
+    "export",
+];
+
+/// Updates the contents of a `package.xml` from the `Cargo.toml` of the package.
+///
+/// The name, version, description, license and maintainers are taken from the Cargo package,
+/// where it has them. Licenses are split into one `<license>` per SPDX identifier, and
+/// maintainers are taken from the authors that have an email address. Cargo dependencies for
+/// which `is_rust_package` is true are added as `<depend>`, or `<test_depend>` for
+/// dev-dependencies, unless they are declared already. Packages that are only exec or build
+/// export dependencies get a `<build_depend>` instead of a duplicate `<depend>`. `<depend>`
+/// entries without a condition for Rust packages that are no longer Cargo dependencies are
+/// removed.
+///
+/// Everything else, like comments, `exec_depend`s, conditions and the `<export>` section, is
+/// kept as it is.
+pub fn sync_package_xml(
+    content: &str,
+    package: &Package,
+    is_rust_package: impl Fn(&str) -> bool,
+) -> Result<String> {
+    let document = roxmltree::Document::parse(content)?;
+    let root = document.root_element();
+    let mut editor = Editor::new(content, root)?;
+
+    let name = match package
+        .metadata
+        .get("ros")
+        .and_then(|ros| ros.get("package_name"))
+    {
+        Some(name) => name
+            .as_str()
+            .ok_or_else(|| anyhow!("[package.metadata.ros] package_name must be a string"))?,
+        None => package.name.as_str(),
+    };
+    editor.set_elements("name", &[format!("<name>{}</name>", xml_escape(name))]);
+    let version = &package.version;
+    editor.set_elements(
+        "version",
+        &[format!(
+            "<version>{}.{}.{}</version>",
+            version.major, version.minor, version.patch
+        )],
+    );
+    if let Some(description) = &package.description {
+        editor.set_elements(
+            "description",
+            &[format!(
+                "<description>{}</description>",
+                xml_escape(description.trim())
+            )],
+        );
+    }
+    let maintainers: Vec<_> = package
+        .authors
+        .iter()
+        .filter_map(|author| {
+            let (name, email) = author.split_once('<')?;
+            let email = email.strip_suffix('>')?;
+            Some(format!(
+                "<maintainer email=\"{}\">{}</maintainer>",
+                xml_escape(email.trim()),
+                xml_escape(name.trim())
+            ))
+        })
+        .collect();
+    if !maintainers.is_empty() {
+        editor.set_elements("maintainer", &maintainers);
+    }
+    if let Some(license) = &package.license {
+        let licenses: Vec<_> = license
+            .split(" OR ")
+            .flat_map(|license| license.split(" AND "))
+            .flat_map(|license| license.split('/'))
+            .map(|license| license.trim_matches(|c: char| c == '(' || c == ')' || c == ' '))
+            .filter(|license| !license.is_empty())
+            .map(|license| format!("<license>{}</license>", xml_escape(license)))
+            .collect();
+        editor.set_elements("license", &licenses);
+    }
+
+    // Dependencies with a condition count as declared, since the condition was added on purpose
+    let declared_types = |name: &str| -> Vec<DependencyType> {
+        root.children()
+            .filter(|node| node.text().map(str::trim) == Some(name))
+            .filter_map(|node| dependency_types(node.tag_name().name()))
+            .flatten()
+            .copied()
+            .collect()
+    };
+    let mut added = Vec::new();
+    for dependency in &package.dependencies {
+        let name = dependency.name.as_str();
+        if !is_rust_package(name) || added.iter().any(|(_, added)| added == name) {
+            continue;
+        }
+        if let Some(tag) = missing_dependency_tag(&dependency.kind, &declared_types(name)) {
+            added.push((tag, name.to_owned()));
+        }
+    }
+    for node in root.children().filter(|node| node.has_tag_name("depend")) {
+        let name = node.text().unwrap_or_default().trim();
+        let is_unused = is_rust_package(name)
+            && !package.dependencies.iter().any(|dep| dep.name == name)
+            && node.attribute("condition").is_none();
+        if is_unused {
+            editor.remove(node.range());
+        }
+    }
+    for tag in ["build_depend", "depend", "test_depend"] {
+        let elements: Vec<_> = added
+            .iter()
+            .filter(|(added_tag, _)| *added_tag == tag)
+            .map(|(_, name)| format!("<{tag}>{}</{tag}>", xml_escape(name)))
+            .collect();
+        editor.append_elements(tag, &elements);
+    }
+
+    let synced = editor.apply();
+    // The edits must not break the package.xml
+    PackageXml::parse(&synced)
+        .map_err(|e| anyhow!("Updating the package.xml failed, this is a bug: {e}"))?;
+    Ok(synced)
+}
+
+/// Updates the `package.xml` at the given path with [`sync_package_xml`], or creates it.
+///
+/// A new `package.xml` is created like by the `init` subcommand before syncing it. Returns
+/// whether the file changed.
+pub fn sync_package_xml_file(
+    path: impl AsRef<Path>,
+    package: &Package,
+    is_rust_package: impl Fn(&str) -> bool,
+) -> Result<bool> {
+    let path = path.as_ref();
+    let content = if path.exists() {
+        std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read '{}'", path.display()))?
+    } else {
+        package_xml(&InitOptions {
+            name: package.name.clone(),
+            ..Default::default()
+        })
+    };
+    let synced = sync_package_xml(&content, package, is_rust_package)
+        .with_context(|| format!("Failed to update '{}'", path.display()))?;
+    if path.exists() && synced == content {
+        return Ok(false);
+    }
+    std::fs::write(path, synced)
+        .with_context(|| format!("Failed to write '{}'", path.display()))?;
+    Ok(true)
+}
+
+/// Collects changes to the text of an XML document, so that all formatting is kept.
+struct Editor<'a, 'input> {
+    content: &'input str,
+    root: roxmltree::Node<'a, 'input>,
+    /// The whitespace that child elements of the root are indented with
+    indent: String,
+    /// The end of the start tag of the root element
+    root_start_end: usize,
+    edits: Vec<(Range<usize>, String)>,
+}
+
+impl<'a, 'input> Editor<'a, 'input> {
+    fn new(content: &'input str, root: roxmltree::Node<'a, 'input>) -> Result<Self> {
+        let indent = root
+            .children()
+            .find(roxmltree::Node::is_element)
+            .map(|node| {
+                let line_start = content[..node.range().start]
+                    .rfind('\n')
+                    .map_or(0, |pos| pos + 1);
+                content[line_start..node.range().start].to_owned()
+            })
+            .filter(|indent| indent.chars().all(|c| c == ' ' || c == '\t'))
+            .unwrap_or_else(|| "  ".to_owned());
+        let root_start_end = match root.first_child() {
+            Some(child) => child.range().start,
+            None => bail!("The <package> element is empty"),
+        };
+        Ok(Self {
+            content,
+            root,
+            indent,
+            root_start_end,
+            edits: Vec::new(),
+        })
+    }
+
+    /// Replaces all elements with a tag by the given ones, at the position of the first one.
+    fn set_elements(&mut self, tag: &str, elements: &[String]) {
+        let existing: Vec<_> = self
+            .root
+            .children()
+            .filter(|node| node.has_tag_name(tag))
+            .collect();
+        let Some((first, others)) = existing.split_first() else {
+            self.insert_elements(tag, elements);
+            return;
+        };
+        let separator = format!("\n{}", self.indent);
+        self.edits.push((first.range(), elements.join(&separator)));
+        for node in others {
+            self.remove(node.range());
+        }
+    }
+
+    /// Adds elements after the last existing element with the same tag.
+    fn append_elements(&mut self, tag: &str, elements: &[String]) {
+        if elements.is_empty() {
+            return;
+        }
+        match self
+            .root
+            .children()
+            .rev()
+            .find(|node| node.has_tag_name(tag))
+        {
+            Some(last) => self.insert_at(last.range().end, elements),
+            None => self.insert_elements(tag, elements),
+        }
+    }
+
+    /// Inserts elements after the elements that precede them in the schema.
+    fn insert_elements(&mut self, tag: &str, elements: &[String]) {
+        // unwrap is ok since only known tags are inserted
+        let position = ELEMENT_ORDER
+            .iter()
+            .position(|known| *known == tag)
+            .unwrap();
+        let preceding = &ELEMENT_ORDER[..=position];
+        let anchor = self
+            .root
+            .children()
+            .rev()
+            .find(|node| node.is_element() && preceding.contains(&node.tag_name().name()));
+        match anchor {
+            Some(anchor) => self.insert_at(anchor.range().end, elements),
+            None => {
+                // Before the first element, indented like the following one
+                let separator = format!("\n{}", self.indent);
+                let text = elements.join(&separator);
+                let position = self.root_start_end;
+                let text = format!("\n{}{text}", self.indent);
+                self.edits.push((position..position, text));
+            }
+        }
+    }
+
+    fn insert_at(&mut self, position: usize, elements: &[String]) {
+        let text: String = elements
+            .iter()
+            .map(|element| format!("\n{}{element}", self.indent))
+            .collect();
+        self.edits.push((position..position, text));
+    }
+
+    /// Removes an element, along with the indentation and line break before it.
+    fn remove(&mut self, range: Range<usize>) {
+        let before = &self.content[..range.start];
+        let trimmed = before.trim_end_matches([' ', '\t']);
+        let start = trimmed
+            .strip_suffix('\n')
+            .map(|trimmed| trimmed.strip_suffix('\r').unwrap_or(trimmed))
+            .map_or(range.start, str::len);
+        self.edits.push((start..range.end, String::new()));
+    }
+
+    fn apply(self) -> String {
+        // The edits are applied from the end, so that the positions of the remaining ones stay
+        // valid. At the same position, removals come before insertions, and insertions end up
+        // in the order they were made.
+        let mut edits: Vec<_> = self.edits.into_iter().enumerate().collect();
+        edits.sort_by_key(|(index, (range, _))| {
+            (
+                std::cmp::Reverse(range.start),
+                range.is_empty(),
+                std::cmp::Reverse(*index),
+            )
+        });
+        let mut content = self.content.to_owned();
+        for (_, (range, text)) in edits {
+            content.replace_range(range, &text);
+        }
+        content
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{dependency, package};
+
+    /// A package with everything that is synced to the package.xml
+    fn my_package() -> Package {
+        package(serde_json::json!({
+            "version": "0.2.0",
+            "description": "Does <things>",
+            "license": "MIT OR Apache-2.0",
+            "authors": ["Jane Doe <jane@example.com>", "Someone without email"],
+            "dependencies": [
+                dependency("rclrs", None),
+                dependency("std_msgs", None),
+                dependency("test_msgs", Some("dev")),
+                dependency("anyhow", None),
+            ],
+        }))
+    }
+
+    #[test]
+    fn test_sync_package_xml() -> Result<()> {
+        let content = r#"<?xml version="1.0"?>
+<package format="3">
+  <name>my_package</name>
+  <version>0.1.0</version>
+  <description>TODO</description>
+  <maintainer email="old@example.com">Old</maintainer>
+  <license>TODO</license>
+
+  <!-- Needed for the launch files -->
+  <exec_depend>ros2launch</exec_depend>
+  <depend>rclrs</depend>
+  <depend>example_interfaces</depend>
+  <depend condition="$ROS_VERSION == 2">geometry_msgs</depend>
+
+  <export>
+    <build_type>ament_cargo</build_type>
+  </export>
+</package>
+"#;
+        let rust_packages = [
+            "rclrs",
+            "std_msgs",
+            "test_msgs",
+            "example_interfaces",
+            "geometry_msgs",
+        ];
+        let synced =
+            sync_package_xml(content, &my_package(), |name| rust_packages.contains(&name))?;
+        assert_eq!(
+            synced,
+            r#"<?xml version="1.0"?>
+<package format="3">
+  <name>my_package</name>
+  <version>0.2.0</version>
+  <description>Does &lt;things&gt;</description>
+  <maintainer email="jane@example.com">Jane Doe</maintainer>
+  <license>MIT</license>
+  <license>Apache-2.0</license>
+
+  <!-- Needed for the launch files -->
+  <exec_depend>ros2launch</exec_depend>
+  <depend>rclrs</depend>
+  <depend condition="$ROS_VERSION == 2">geometry_msgs</depend>
+  <depend>std_msgs</depend>
+  <test_depend>test_msgs</test_depend>
+
+  <export>
+    <build_type>ament_cargo</build_type>
+  </export>
+</package>
+"#
+        );
+        // Syncing again changes nothing
+        assert_eq!(
+            sync_package_xml(&synced, &my_package(), |name| rust_packages.contains(&name))?,
+            synced
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_keeps_exec_depend() -> Result<()> {
+        let content = r#"<package format="3">
+  <name>my_package</name>
+  <version>0.2.0</version>
+  <exec_depend>rclrs</exec_depend>
+  <export><build_type>ament_cargo</build_type></export>
+</package>
+"#;
+        let synced = sync_package_xml(content, &my_package(), |name| name == "rclrs")?;
+        // A <depend> would declare the exec dependency twice
+        assert!(
+            synced.contains(
+                "  <build_depend>rclrs</build_depend>\n  <exec_depend>rclrs</exec_depend>\n"
+            ),
+            "{synced}"
+        );
+        assert!(!synced.contains("<depend>"), "{synced}");
+        let package_xml = PackageXml::parse(&synced)?;
+        assert!(package_xml
+            .lint_dependencies(&my_package(), |name| name == "rclrs")
+            .is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_inserts_missing_elements() -> Result<()> {
+        let content = "<package format=\"3\">\n\t<name>my_package</name>\n\t<export><build_type>ament_cargo</build_type></export>\n</package>\n";
+        let synced = sync_package_xml(content, &my_package(), |name| name == "rclrs")?;
+        assert_eq!(
+            synced,
+            "<package format=\"3\">\n\t<name>my_package</name>\n\t<version>0.2.0</version>\n\t<description>Does &lt;things&gt;</description>\n\t<maintainer email=\"jane@example.com\">Jane Doe</maintainer>\n\t<license>MIT</license>\n\t<license>Apache-2.0</license>\n\t<depend>rclrs</depend>\n\t<export><build_type>ament_cargo</build_type></export>\n</package>\n"
+        );
+        Ok(())
+    }
+}
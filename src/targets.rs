@@ -0,0 +1,261 @@
+// Licensed under the Apache License, Version 2.0
+
+use std::ffi::OsString;
+
+/// The package and target selection flags given to cargo.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TargetSelection {
+    /// The packages given with `-p`/`--package`
+    pub packages: Vec<String>,
+    /// Whether `--workspace` (or its alias `--all`) was given
+    pub workspace: bool,
+    /// Whether `--lib` was given
+    pub lib: bool,
+    /// The names given with `--bin`
+    pub bins: Vec<String>,
+    /// Whether `--bins` was given
+    pub all_bins: bool,
+    /// Whether examples were selected with `--example` or `--examples`, which are not installed
+    pub examples: bool,
+    /// Whether `--all-targets` was given
+    pub all_targets: bool,
+    /// Whether tests or benches were selected with `--test`, `--tests`, `--bench` or `--benches`
+    pub tests_or_benches: bool,
+    /// Whether only the doc tests were selected with `--doc`
+    pub doc: bool,
+    /// The packages given with `--exclude`
+    pub excluded_packages: Vec<String>,
+}
+
+impl TargetSelection {
+    /// Extracts the package and target selection flags from arguments for cargo.
+    ///
+    /// Like cargo, this accepts `--package pkg`, `--package=pkg`, `-p pkg` and `-ppkg`.
+    pub fn from_args(args: &[OsString]) -> Self {
+        let mut selection = Self::default();
+        let mut args = args.iter().map(|arg| arg.to_string_lossy());
+        while let Some(arg) = args.next() {
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag, Some(value.to_owned())),
+                None => match arg.strip_prefix("-p").filter(|value| !value.is_empty()) {
+                    Some(value) => ("-p", Some(value.to_owned())),
+                    None => (arg.as_ref(), None),
+                },
+            };
+            let list = match flag {
+                "--workspace" | "--all" => {
+                    selection.workspace = true;
+                    continue;
+                }
+                "--lib" => {
+                    selection.lib = true;
+                    continue;
+                }
+                "--bins" => {
+                    selection.all_bins = true;
+                    continue;
+                }
+                "--all-targets" => {
+                    selection.all_targets = true;
+                    continue;
+                }
+                "--tests" | "--benches" => {
+                    selection.tests_or_benches = true;
+                    continue;
+                }
+                "--doc" => {
+                    selection.doc = true;
+                    continue;
+                }
+                "-p" | "--package" => &mut selection.packages,
+                "--bin" => &mut selection.bins,
+                "--exclude" => &mut selection.excluded_packages,
+                // The names of examples, tests and benches are not needed
+                "--example" | "--examples" => {
+                    selection.examples = true;
+                    if flag == "--example" && value.is_none() {
+                        args.next();
+                    }
+                    continue;
+                }
+                "--test" | "--bench" => {
+                    selection.tests_or_benches = true;
+                    if value.is_none() {
+                        args.next();
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            if let Some(value) = value.or_else(|| args.next().map(String::from)) {
+                list.push(value);
+            }
+        }
+        selection
+    }
+
+    /// Whether no targets were selected explicitly, in which case cargo builds the library and
+    /// all binaries.
+    pub fn is_default(&self) -> bool {
+        !self.lib
+            && self.bins.is_empty()
+            && !self.all_bins
+            && !self.examples
+            && !self.all_targets
+            && !self.tests_or_benches
+            && !self.doc
+    }
+
+    /// Whether workspace members were selected with `--workspace` or `--package`, instead of
+    /// building the package in the current directory.
+    pub fn selects_workspace_members(&self) -> bool {
+        self.workspace || !self.packages.is_empty()
+    }
+
+    /// Whether the package is built at all, i.e. selected with `--package` if that was given and
+    /// not excluded with `--exclude`.
+    pub fn selects_package(&self, package_name: &str) -> bool {
+        // A package spec can also contain a version, as in `name@1.0.0`
+        let selected = self.packages.is_empty()
+            || self
+                .packages
+                .iter()
+                .any(|spec| spec.split('@').next() == Some(package_name));
+        let excluded = self
+            .excluded_packages
+            .iter()
+            .any(|excluded| excluded == package_name);
+        selected && !excluded
+    }
+
+    /// Whether all targets of the package that are installed are built.
+    pub fn selects_everything(&self, package_name: &str) -> bool {
+        self.selects_package(package_name) && (self.is_default() || self.all_targets)
+    }
+
+    /// Whether the binary with the given name is built.
+    pub fn selects_bin(&self, package_name: &str, bin_name: &str) -> bool {
+        self.selects_package(package_name)
+            && (self.is_default()
+                || self.all_bins
+                || self.all_targets
+                || self.bins.iter().any(|bin| bin == bin_name))
+    }
+}
+
+/// Removes the package selection flags from arguments for cargo, so that other packages can be
+/// selected instead. Arguments after `--` are kept as they are.
+pub fn without_package_selection(args: &[OsString]) -> Vec<OsString> {
+    let mut kept = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            kept.push(arg.clone());
+            kept.extend(args.cloned());
+            break;
+        }
+        match arg.to_string_lossy().as_ref() {
+            "--workspace" | "--all" => {}
+            "-p" | "--package" | "--exclude" => {
+                args.next();
+            }
+            flag if flag.starts_with("-p")
+                || flag.starts_with("--package=")
+                || flag.starts_with("--exclude=") => {}
+            _ => kept.push(arg.clone()),
+        }
+    }
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn test_default_selection() {
+        let selection = TargetSelection::from_args(&args(&["--release", "--features", "lib"]));
+
+        assert!(selection.is_default());
+        assert!(selection.selects_everything("pkg"));
+        assert!(selection.selects_bin("pkg", "node"));
+    }
+
+    #[test]
+    fn test_single_binary() {
+        let selection = TargetSelection::from_args(&args(&["--bin", "talker", "--bin=listener"]));
+
+        assert_eq!(selection.bins, ["talker", "listener"]);
+        assert!(!selection.selects_everything("pkg"));
+        assert!(selection.selects_bin("pkg", "talker"));
+        assert!(selection.selects_bin("pkg", "listener"));
+        assert!(!selection.selects_bin("pkg", "other_node"));
+    }
+
+    #[test]
+    fn test_package_selection() {
+        let selection =
+            TargetSelection::from_args(&args(&["-p", "talker", "--package=listener@0.1.0"]));
+
+        assert!(selection.selects_workspace_members());
+        assert!(selection.selects_package("talker"));
+        assert!(selection.selects_package("listener"));
+        assert!(!selection.selects_package("other_pkg"));
+
+        let selection = TargetSelection::from_args(&args(&["-ptalker", "-p=listener"]));
+        assert_eq!(selection.packages, ["talker", "listener"]);
+
+        let selection = TargetSelection::from_args(&args(&["--workspace", "--exclude", "talker"]));
+        assert!(selection.selects_workspace_members());
+        assert!(!selection.selects_package("talker"));
+        assert!(selection.selects_package("listener"));
+    }
+
+    #[test]
+    fn test_examples_tests_and_excluded_packages() {
+        let selection = TargetSelection::from_args(&args(&[
+            "--example",
+            "demo",
+            "--test",
+            "integration",
+            "--lib",
+            "--exclude",
+            "other_pkg",
+        ]));
+
+        assert!(selection.examples);
+        assert!(selection.tests_or_benches);
+        assert!(selection.lib);
+        assert!(!selection.selects_everything("pkg"));
+        assert!(!selection.selects_bin("pkg", "integration"));
+        assert!(!selection.selects_package("other_pkg"));
+
+        let selection = TargetSelection::from_args(&args(&["--all-targets"]));
+        assert!(selection.selects_everything("pkg"));
+
+        let selection = TargetSelection::from_args(&args(&["--doc"]));
+        assert!(selection.doc);
+        assert!(!selection.is_default());
+    }
+
+    #[test]
+    fn test_without_package_selection() {
+        let kept = without_package_selection(&args(&[
+            "--workspace",
+            "-p",
+            "talker",
+            "-plistener",
+            "--package=util",
+            "--exclude",
+            "other_pkg",
+            "--release",
+            "--",
+            "-p",
+        ]));
+        assert_eq!(kept, args(&["--release", "--", "-p"]));
+    }
+}
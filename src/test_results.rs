@@ -0,0 +1,420 @@
+// Licensed under the Apache License, Version 2.0
+
+use anyhow::{Context, Result};
+use cargo_metadata::{Artifact, Package, PackageId};
+use serde_json::Value;
+
+use crate::init::xml_escape;
+use crate::targets::without_package_selection;
+
+use std::ffi::OsString;
+use std::fmt::Write;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// How a single test ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TestOutcome {
+    Passed,
+    Failed,
+    Ignored,
+}
+
+/// A test and its result
+#[derive(Clone, Debug, PartialEq)]
+pub struct TestCase {
+    pub name: String,
+    pub outcome: TestOutcome,
+    /// The captured output of a failed test
+    pub output: String,
+    /// The duration in seconds, if libtest reported it
+    pub time: Option<f64>,
+}
+
+/// The tests run by one test executable, or the doc tests of a crate
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TestSuite {
+    /// How cargo announced the suite, e.g. `unittests src/lib.rs` or `doctests`
+    pub name: String,
+    /// The name of the crate that the tests belong to
+    pub crate_name: String,
+    pub cases: Vec<TestCase>,
+}
+
+/// The results of one test executable, or of the doc tests of a package
+pub struct TestRun {
+    /// The package that the tests belong to
+    pub package_id: PackageId,
+    /// Whether the tests passed, i.e. the test executable exited successfully
+    pub success: bool,
+    pub suite: TestSuite,
+}
+
+/// Runs a test executable that `cargo test --no-run` built, like `cargo test` would, and collects
+/// its test results.
+///
+/// `args` are the arguments for cargo, whose arguments after `--` are passed to the executable.
+/// The executable runs in the directory of its package, with `CARGO_MANIFEST_DIR`,
+/// `CARGO_PKG_NAME` and `CARGO_PKG_VERSION` set. Its stdout is passed through while the results
+/// are read from it, and its stderr is left alone, so the output keeps its order.
+pub fn run_test_executable(
+    artifact: &Artifact,
+    package: &Package,
+    args: &[OsString],
+    envs: &[(&str, String)],
+) -> Result<TestRun> {
+    let executable = artifact
+        .executable
+        .as_ref()
+        .with_context(|| format!("'{}' is not a test executable", artifact.target.name))?;
+    // unwrap is ok since the manifest path is a file
+    let package_dir = package.manifest_path.parent().unwrap();
+    let src_path = &artifact.target.src_path;
+    let src_path = src_path.strip_prefix(package_dir).unwrap_or(src_path);
+    // Like cargo announces them
+    let name = if artifact.target.is_test() || artifact.target.is_bench() {
+        src_path.to_string()
+    } else {
+        format!("unittests {src_path}")
+    };
+    eprintln!("     Running {name} ({executable})");
+    let mut cmd = Command::new(executable);
+    cmd.args(test_args(args));
+    cmd.current_dir(package_dir);
+    cmd.env("CARGO_MANIFEST_DIR", package_dir);
+    cmd.env("CARGO_PKG_NAME", &package.name);
+    cmd.env("CARGO_PKG_VERSION", package.version.to_string());
+    cmd.envs(envs.iter().map(|(key, value)| (key, value)));
+    let (success, cases) =
+        run_and_parse(cmd).with_context(|| format!("Failed to run '{executable}'"))?;
+    Ok(TestRun {
+        package_id: artifact.package_id.clone(),
+        success,
+        suite: TestSuite {
+            name,
+            crate_name: artifact.target.name.replace('-', "_"),
+            cases,
+        },
+    })
+}
+
+/// Runs the doc tests of a package with `cargo test --doc` and collects their results.
+///
+/// `args` are the arguments for cargo, whose package selection is replaced by the package.
+pub fn run_doc_tests(
+    package: &Package,
+    args: &[OsString],
+    envs: &[(&str, String)],
+) -> Result<TestRun> {
+    let mut cmd = Command::new("cargo");
+    cmd.args(["test", "--doc", "--package", &package.name]);
+    cmd.args(
+        without_package_selection(args)
+            .into_iter()
+            .filter(|arg| arg != "--doc"),
+    );
+    cmd.envs(envs.iter().map(|(key, value)| (key, value)));
+    // Cargo announces the doc tests on stderr, and libtest writes the results to stdout
+    let (success, cases) = run_and_parse(cmd).context("Failed to run 'cargo test --doc'")?;
+    let crate_name = package
+        .targets
+        .iter()
+        .find(|target| target.is_lib())
+        .map_or(package.name.as_str(), |target| target.name.as_str());
+    Ok(TestRun {
+        package_id: package.id.clone(),
+        success,
+        suite: TestSuite {
+            name: "doctests".to_owned(),
+            crate_name: crate_name.replace('-', "_"),
+            cases,
+        },
+    })
+}
+
+/// The arguments after `--`, which are meant for the test executables.
+fn test_args(args: &[OsString]) -> &[OsString] {
+    match args.iter().position(|arg| arg == "--") {
+        Some(dash_dash) => &args[dash_dash + 1..],
+        None => &[],
+    }
+}
+
+/// Runs a command whose stdout is the output of libtest, and returns whether it succeeded along
+/// with the test results.
+fn run_and_parse(mut cmd: Command) -> Result<(bool, Vec<TestCase>)> {
+    cmd.stdout(Stdio::piped());
+    let mut child = cmd.spawn()?;
+    // unwrap is ok since stdout is piped
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let cases = parse_test_output(stdout.lines(), |line| println!("{line}"))?;
+    let exit_status = child.wait()?;
+    if !exit_status.success() {
+        eprintln!("error: The tests did not exit successfully ({exit_status})");
+    }
+    Ok((exit_status.success(), cases))
+}
+
+/// Parses the lines of the output of a test executable, passing each line to `echo`.
+///
+/// Both the default output of libtest and its JSON output (`-Z unstable-options --format json`)
+/// are understood.
+pub fn parse_test_output(
+    lines: impl IntoIterator<Item = std::io::Result<String>>,
+    mut echo: impl FnMut(&str),
+) -> Result<Vec<TestCase>> {
+    let mut cases: Vec<TestCase> = Vec::new();
+    // The name of the failed test whose output is being read
+    let mut failure: Option<String> = None;
+    for line in lines {
+        let line = line.context("Failed to read the output of the tests")?;
+        echo(&line);
+        if let Some(case) = parse_json_event(line.trim()) {
+            cases.push(case);
+        } else if let Some((name, result)) = line
+            .strip_prefix("test ")
+            .and_then(|rest| rest.rsplit_once(" ... "))
+        {
+            let outcome = match result {
+                "ok" => TestOutcome::Passed,
+                "FAILED" => TestOutcome::Failed,
+                result if result.starts_with("ignored") => TestOutcome::Ignored,
+                // E.g. benchmarks
+                _ => continue,
+            };
+            cases.push(TestCase {
+                name: name.to_owned(),
+                outcome,
+                output: String::new(),
+                time: None,
+            });
+        } else if let Some(name) = line
+            .strip_prefix("---- ")
+            .and_then(|rest| rest.strip_suffix(" stdout ----"))
+        {
+            failure = Some(name.to_owned());
+        } else if line == "failures:" || line.starts_with("test result: ") {
+            failure = None;
+        } else if let Some(name) = &failure {
+            if let Some(case) = cases.iter_mut().find(|case| &case.name == name) {
+                case.output.push_str(&line);
+                case.output.push('\n');
+            }
+        }
+    }
+    for case in &mut cases {
+        case.output = case.output.trim().to_owned();
+    }
+    Ok(cases)
+}
+
+/// Parses a test event of libtest's JSON output.
+fn parse_json_event(line: &str) -> Option<TestCase> {
+    if !line.starts_with('{') {
+        return None;
+    }
+    let event: Value = serde_json::from_str(line).ok()?;
+    if event.get("type")?.as_str()? != "test" {
+        return None;
+    }
+    let outcome = match event.get("event")?.as_str()? {
+        "ok" => TestOutcome::Passed,
+        "failed" | "timeout" => TestOutcome::Failed,
+        "ignored" => TestOutcome::Ignored,
+        _ => return None,
+    };
+    let output = ["stdout", "message"]
+        .iter()
+        .filter_map(|key| event.get(*key).and_then(Value::as_str))
+        .collect::<Vec<_>>()
+        .join("\n");
+    Some(TestCase {
+        name: event.get("name")?.as_str()?.to_owned(),
+        outcome,
+        output: output.trim().to_owned(),
+        time: event.get("exec_time").and_then(Value::as_f64),
+    })
+}
+
+/// Writes the test results of a package as JUnit XML, which `colcon test-result` understands.
+pub fn write_junit(
+    path: impl AsRef<Path>,
+    package_name: &str,
+    suites: &[&TestSuite],
+) -> Result<()> {
+    let path = path.as_ref();
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, junit_xml(package_name, suites))
+        .with_context(|| format!("Failed to write test results '{}'", path.display()))
+}
+
+fn junit_xml(package_name: &str, suites: &[&TestSuite]) -> String {
+    let count = |outcome: TestOutcome, cases: &[TestCase]| {
+        cases.iter().filter(|case| case.outcome == outcome).count()
+    };
+    let all_cases: Vec<_> = suites
+        .iter()
+        .flat_map(|suite| suite.cases.iter().cloned())
+        .collect();
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    // unwrap is ok since writing to a string cannot fail
+    writeln!(
+        xml,
+        "<testsuites name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"0\" skipped=\"{}\">",
+        escape(package_name),
+        all_cases.len(),
+        count(TestOutcome::Failed, &all_cases),
+        count(TestOutcome::Ignored, &all_cases)
+    )
+    .unwrap();
+    for suite in suites {
+        let classname = escape(&format!("{package_name}.{}", suite.crate_name));
+        writeln!(
+            xml,
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"0\" skipped=\"{}\">",
+            escape(&format!(
+                "{package_name}.{} {}",
+                suite.crate_name, suite.name
+            )),
+            suite.cases.len(),
+            count(TestOutcome::Failed, &suite.cases),
+            count(TestOutcome::Ignored, &suite.cases)
+        )
+        .unwrap();
+        for case in &suite.cases {
+            write!(
+                xml,
+                "    <testcase classname=\"{classname}\" name=\"{}\" time=\"{}\"",
+                escape(&case.name),
+                case.time.unwrap_or_default()
+            )
+            .unwrap();
+            match case.outcome {
+                TestOutcome::Passed if case.output.is_empty() => xml.push_str("/>\n"),
+                TestOutcome::Passed => writeln!(
+                    xml,
+                    ">\n      <system-out>{}</system-out>\n    </testcase>",
+                    escape(&case.output)
+                )
+                .unwrap(),
+                TestOutcome::Ignored => xml.push_str(">\n      <skipped/>\n    </testcase>\n"),
+                TestOutcome::Failed => writeln!(
+                    xml,
+                    ">\n      <failure message=\"Test failed\">{}</failure>\n    </testcase>",
+                    escape(&case.output)
+                )
+                .unwrap(),
+            }
+        }
+        xml.push_str("  </testsuite>\n");
+    }
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+/// Escapes text for XML, dropping control characters like color codes, which XML 1.0 forbids.
+fn escape(text: &str) -> String {
+    let text: String = text
+        .chars()
+        .filter(|c| !c.is_control() || matches!(c, '\t' | '\n' | '\r'))
+        .collect();
+    xml_escape(&text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OUTPUT: &str = "
+running 3 tests
+test tests::it_works ... ok
+test tests::it_fails ... FAILED
+test tests::slow ... ignored, takes too long
+
+failures:
+
+---- tests::it_fails stdout ----
+
+thread 'tests::it_fails' panicked at src/lib.rs:10:9:
+assertion failed: 1 < 0
+
+failures:
+    tests::it_fails
+
+test result: FAILED. 1 passed; 1 failed; 1 ignored; 0 measured; 0 filtered out; finished in 0.00s
+";
+
+    const JSON_OUTPUT: &str = "{ \"type\": \"suite\", \"event\": \"started\", \"test_count\": 1 }
+{ \"type\": \"test\", \"event\": \"ok\", \"name\": \"roundtrip\", \"exec_time\": 0.25 }
+";
+
+    fn suite(name: &str, output: &str) -> Result<TestSuite> {
+        Ok(TestSuite {
+            name: name.to_owned(),
+            crate_name: "my_package".to_owned(),
+            cases: parse_test_output(output.as_bytes().lines(), |_| {})?,
+        })
+    }
+
+    #[test]
+    fn test_parse_test_output() -> Result<()> {
+        let mut echoed = 0;
+        let cases = parse_test_output(OUTPUT.as_bytes().lines(), |_| echoed += 1)?;
+        assert_eq!(echoed, OUTPUT.lines().count());
+        assert_eq!(
+            cases.iter().map(|case| case.outcome).collect::<Vec<_>>(),
+            [
+                TestOutcome::Passed,
+                TestOutcome::Failed,
+                TestOutcome::Ignored
+            ]
+        );
+        assert_eq!(
+            cases[1].output,
+            "thread 'tests::it_fails' panicked at src/lib.rs:10:9:\nassertion failed: 1 < 0"
+        );
+
+        let cases = parse_test_output(JSON_OUTPUT.as_bytes().lines(), |_| {})?;
+        assert_eq!(cases.len(), 1);
+        assert_eq!(cases[0].name, "roundtrip");
+        assert_eq!(cases[0].time, Some(0.25));
+        Ok(())
+    }
+
+    #[test]
+    fn test_test_args() {
+        let args: Vec<OsString> = ["--release", "--", "--nocapture"]
+            .into_iter()
+            .map(OsString::from)
+            .collect();
+        assert_eq!(test_args(&args), [OsString::from("--nocapture")]);
+        assert!(test_args(&args[..1]).is_empty());
+    }
+
+    #[test]
+    fn test_junit_xml() -> Result<()> {
+        let suites = [
+            suite("unittests src/lib.rs", OUTPUT)?,
+            suite("tests/json.rs", JSON_OUTPUT)?,
+        ];
+        let xml = junit_xml("my_package", &suites.iter().collect::<Vec<_>>());
+        let document = roxmltree::Document::parse(&xml)?;
+        let root = document.root_element();
+        assert_eq!(root.attribute("tests"), Some("4"));
+        assert_eq!(root.attribute("failures"), Some("1"));
+        assert_eq!(root.attribute("skipped"), Some("1"));
+        let failure = document
+            .descendants()
+            .find(|node| node.has_tag_name("failure"))
+            .unwrap();
+        assert_eq!(
+            failure.parent().and_then(|case| case.attribute("name")),
+            Some("tests::it_fails")
+        );
+        assert!(failure.text().unwrap().contains("assertion failed: 1 < 0"));
+        Ok(())
+    }
+}
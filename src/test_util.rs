@@ -0,0 +1,85 @@
+// Licensed under the Apache License, Version 2.0
+
+use cargo_metadata::{Artifact, Package, Target};
+use serde_json::{json, Value};
+
+use std::path::PathBuf;
+
+/// Creates a package as listed by `cargo metadata`.
+///
+/// The package is `my_package` 0.1.0 in `/ws/my_package`, without dependencies, targets and
+/// features. The given fields are set in addition, or replace these.
+pub fn package(fields: Value) -> Package {
+    let mut package = json!({
+        "name": "my_package",
+        "version": "0.1.0",
+        "id": "path+file:///ws/my_package#0.1.0",
+        "dependencies": [],
+        "targets": [],
+        "features": {},
+        "manifest_path": "/ws/my_package/Cargo.toml",
+    });
+    let fields = fields
+        .as_object()
+        .expect("The fields must be an object")
+        .clone();
+    // unwrap is ok since the package is an object
+    package.as_object_mut().unwrap().extend(fields);
+    serde_json::from_value(package).unwrap()
+}
+
+/// Creates a dependency of a package as listed by `cargo metadata`, with a kind such as `"dev"`,
+/// or None for a normal dependency.
+pub fn dependency(name: &str, kind: Option<&str>) -> Value {
+    json!({
+        "name": name,
+        "req": "*",
+        "kind": kind,
+        "optional": false,
+        "uses_default_features": true,
+        "features": [],
+        "target": null,
+    })
+}
+
+/// Creates a binary target as listed by `cargo metadata`.
+pub fn bin_target(name: &str, required_features: &[&str]) -> Target {
+    serde_json::from_value(json!({
+        "name": name,
+        "kind": ["bin"],
+        "crate_types": ["bin"],
+        "required-features": required_features,
+        "src_path": format!("/pkg/src/bin/{name}.rs"),
+    }))
+    .unwrap()
+}
+
+/// Creates an artifact as cargo would report it in a `compiler-artifact` message.
+pub fn artifact(
+    name: &str,
+    kind: &[&str],
+    filenames: &[PathBuf],
+    executable: Option<PathBuf>,
+) -> Artifact {
+    serde_json::from_value(json!({
+        "package_id": "path+file:///pkg#0.1.0",
+        "manifest_path": "/pkg/Cargo.toml",
+        "target": {
+            "name": name,
+            "kind": kind,
+            "crate_types": kind,
+            "src_path": "/pkg/src/main.rs",
+        },
+        "profile": {
+            "opt_level": "0",
+            "debug_assertions": true,
+            "overflow_checks": true,
+            "test": false,
+        },
+        "features": [],
+        "filenames": filenames,
+        "executable": executable,
+        "fresh": false,
+    }))
+    .unwrap()
+}